@@ -0,0 +1,138 @@
+//! Compares formatting a streamed response from scratch after every update, without caching
+//! code blocks, with formatting it incrementally like the UI does.
+//! Run it with `cargo bench --bench render`.
+
+// Only the formatting code is needed, so the modules are included directly
+#![allow(dead_code)]
+// Their unit tests are left out of the benchmark, but their imports are still compiled
+#![cfg_attr(test, allow(unused_imports))]
+
+#[path = "../src/format.rs"]
+mod format;
+#[path = "../src/message.rs"]
+mod message;
+
+use std::time::{Duration, Instant};
+
+use cursive::utils::markup::StyledString;
+use syntect::dumps::from_binary;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+
+use format::{Formatter, StreamingMessage, CODE_CACHE_SIZE};
+use message::{Message, Role};
+
+/// The number of bytes in each streamed update, roughly a few tokens
+const CHUNK_SIZE: usize = 12;
+
+/// A part of the response, which is repeated with `{n}` replaced so the code blocks differ
+const SECTION: &str = r#"Here is how you could read file number {n} line by line, and count the words on each line:
+
+```rust
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+fn main() -> std::io::Result<()> {
+    let reader = BufReader::new(File::open("input-{n}.txt")?);
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        println!("{}: {} words", number + 1, line.split_whitespace().count());
+    }
+
+    Ok(())
+}
+```
+
+A few things to note:
+
+1. `BufReader` avoids a system call for every line.
+2. `lines()` strips the line endings, so **no trimming** is needed.
+3. Errors are returned with `?` instead of calling `unwrap`.
+
+If the file is large, you could also process it in parallel:
+
+```python
+from concurrent.futures import ThreadPoolExecutor
+
+def count_words(line):
+    return len(line.split())
+
+with open("input-{n}.txt") as f, ThreadPoolExecutor() as pool:
+    for number, words in enumerate(pool.map(count_words, f), start=1):
+        print(f"{number}: {words} words")
+```
+
+"#;
+
+fn main() {
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let theme_set: ThemeSet = from_binary(include_bytes!("../assets/ansi.bin"));
+    let theme = theme_set.themes["ansi"].to_owned();
+
+    let full_formatter = Formatter::new(syntax_set.to_owned(), theme.to_owned(), 0);
+    let formatter = Formatter::new(syntax_set, theme, CODE_CACHE_SIZE);
+
+    // Syntaxes are loaded lazily, so highlight some code before timing anything
+    for formatter in [&full_formatter, &formatter] {
+        formatter.format_message(&assistant_message(
+            "```rust\nfn main() {}\n```\n\n```python\nprint()\n```",
+        ));
+    }
+
+    for sections in [1, 4, 16] {
+        let response: String = (0..sections)
+            .map(|n| SECTION.replace("{n}", &n.to_string()))
+            .collect();
+
+        let (full, full_time) = stream(&response, |m| full_formatter.format_message(m));
+
+        let mut streaming = StreamingMessage::default();
+        let (incremental, incremental_time) =
+            stream(&response, |m| streaming.format(&formatter, m));
+
+        assert_eq!(
+            full.source(),
+            incremental.source(),
+            "the incremental formatting differs from the full formatting"
+        );
+
+        println!("Response of {} bytes:", response.len());
+        println!("  full:        {full_time:>10.2?}");
+        println!(
+            "  incremental: {incremental_time:>10.2?} ({:.1}x faster)",
+            full_time.as_secs_f64() / incremental_time.as_secs_f64()
+        );
+    }
+}
+
+fn assistant_message(content: &str) -> Message {
+    Message {
+        role: Role::Assistant,
+        content: content.to_owned(),
+        ..Default::default()
+    }
+}
+
+/// Streams `response` in small chunks, formatting the message after each one.
+/// Returns the last formatting and the total time spent formatting.
+fn stream(
+    response: &str,
+    mut format: impl FnMut(&Message) -> StyledString,
+) -> (StyledString, Duration) {
+    let mut message = assistant_message("");
+    let mut formatted = StyledString::new();
+    let mut elapsed = Duration::ZERO;
+
+    for chunk in response.as_bytes().chunks(CHUNK_SIZE) {
+        message
+            .content
+            .push_str(std::str::from_utf8(chunk).unwrap());
+
+        let start = Instant::now();
+        formatted = format(&message);
+        elapsed += start.elapsed();
+    }
+
+    (formatted, elapsed)
+}
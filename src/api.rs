@@ -1,130 +1,763 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::str;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Sender};
 
-use futures::AsyncBufReadExt;
+use chrono::Local;
+use futures::{AsyncBufRead, AsyncBufReadExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use surf::Client;
+use surf::{Client, RequestBuilder, Response, StatusCode};
 
+use crate::config::{AuthScheme, Profile, Provider};
+use crate::message::{ContentPart, FunctionCall, Message, MessageMetadata, Role, ToolCall, Usage};
 use crate::ProcessedMessage;
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum Role {
-    #[serde(rename = "user")]
-    User,
-    #[serde(rename = "system")]
-    System,
-    #[serde(rename = "assistant")]
-    Assistant,
-}
+mod anthropic;
+use anthropic::{stream_anthropic_response, ANTHROPIC_VERSION};
+
+mod ollama;
+use ollama::{list_ollama_models, stream_ollama_response};
 
-#[derive(Serialize, Deserialize)]
-pub enum FinishReason {
-    #[serde(rename = "stop")]
-    Stop,
+#[derive(Serialize)]
+pub struct RequestMessage<'a> {
+    role: &'a Role,
+    content: RequestContent<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tool_calls: &'a [ToolCall],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Message {
-    pub role: Role,
-    pub content: String,
+/// The content of a message is only sent as an array of parts if it has more than text, since
+/// not every compatible server accepts arrays
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RequestContent<'a> {
+    Text(Cow<'a, str>),
+    Parts(Vec<ContentPart>),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Choice {
-    message: Message,
-    finish_reason: FinishReason,
-    index: i32,
+impl<'a> From<&'a Message> for RequestContent<'a> {
+    fn from(m: &'a Message) -> Self {
+        let content = m.sent_content();
+
+        if m.parts.is_empty() {
+            return RequestContent::Text(content);
+        }
+
+        let text = (!content.is_empty()).then(|| ContentPart::Text {
+            text: content.into_owned(),
+        });
+
+        RequestContent::Parts(text.into_iter().chain(m.parts.to_owned()).collect())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ApiRequest {
-    model: &'static str,
-    messages: Vec<Message>,
+#[derive(Serialize)]
+pub struct ApiRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage<'a>>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    /// The local tools that the model can call, as JSON schemas
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [Value],
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only sent in the last chunk, when `stream_options.include_usage` is set
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Delta,
+    finish_reason: Option<String>,
+    content_filter_results: Option<ContentFilterResults>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ApiResponse {
-    choices: Vec<Choice>,
+#[derive(Deserialize, Default)]
+struct Delta {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A fragment of a tool call. Only the first fragment of a call has its ID and name, the
+/// arguments are split across the fragments.
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    #[serde(default)]
+    function: FunctionDelta,
+}
+
+#[derive(Deserialize, Default)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: Option<ApiError>,
+    /// Some Azure errors (e.g. from the API gateway) are not wrapped in an `error` object
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+    innererror: Option<InnerError>,
+}
+
+#[derive(Deserialize)]
+struct InnerError {
+    content_filter_result: Option<ContentFilterResults>,
+}
+
+/// Azure content filter results, keyed by category (e.g. `hate`, `violence`)
+type ContentFilterResults = BTreeMap<String, ContentFilterResult>;
+
+#[derive(Deserialize)]
+struct ContentFilterResult {
+    #[serde(default)]
+    filtered: bool,
+    severity: Option<String>,
 }
 
-pub async fn stream_chatgpt_response(
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelObject>,
+}
+
+#[derive(Deserialize)]
+struct ModelObject {
+    id: String,
+}
+
+/// Parts of model names that can't be used for chat completions
+const NON_CHAT_MODELS: &[&str] = &[
+    "embedding",
+    "moderation",
+    "whisper",
+    "tts",
+    "dall-e",
+    "image",
+    "audio",
+    "realtime",
+    "transcribe",
+    "instruct",
+    "davinci",
+    "babbage",
+];
+
+/// Returns a description of each category that was filtered, e.g. `violence (medium)`
+fn filtered_categories(results: &ContentFilterResults) -> Vec<String> {
+    results
+        .iter()
+        .filter(|(_, result)| result.filtered)
+        .map(|(category, result)| match &result.severity {
+            Some(severity) => format!("{category} ({severity})"),
+            None => category.to_owned(),
+        })
+        .collect()
+}
+
+fn parse_error(body: &str) -> String {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(ErrorResponse {
+            error: Some(error), ..
+        }) => {
+            let filtered = error
+                .innererror
+                .and_then(|e| e.content_filter_result)
+                .map(|results| filtered_categories(&results))
+                .unwrap_or_default();
+
+            if filtered.is_empty() {
+                error.message
+            } else {
+                format!(
+                    "The prompt was blocked by the content filter: {}\n\n{}",
+                    filtered.join(", "),
+                    error.message
+                )
+            }
+        }
+        Ok(ErrorResponse {
+            message: Some(message),
+            ..
+        }) => message,
+        _ => body.to_owned(),
+    }
+}
+
+fn chat_completions_url(profile: &Profile) -> String {
+    match profile.provider {
+        Provider::Azure => format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            profile.base_url(),
+            profile.deployment(),
+            profile.api_version
+        ),
+        _ => format!("{}/chat/completions", profile.base_url()),
+    }
+}
+
+/// Streams a response to `messages` from the provider of the given profile.
+/// New text is sent to the UI as it arrives, and the complete message is returned.
+/// `tools` are only offered to OpenAI and Azure models.
+pub async fn stream_response(
     client: &Client,
-    apikey: &String,
-    messages: &Vec<Message>,
+    profile: &Profile,
+    messages: &[Message],
+    tools: &[Value],
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<Message, String> {
+    match profile.provider {
+        Provider::OpenAi | Provider::Azure => {
+            stream_chatgpt_response(client, profile, messages, tools, processed_msg_send).await
+        }
+        Provider::Anthropic => {
+            stream_anthropic_response(client, profile, messages, processed_msg_send).await
+        }
+        Provider::Ollama => {
+            stream_ollama_response(client, profile, messages, processed_msg_send).await
+        }
+    }
+}
+
+/// Gets a complete response, without showing it in the UI while it is streamed
+pub async fn complete_response(
+    client: &Client,
+    profile: &Profile,
+    messages: &[Message],
+) -> Result<Message, String> {
+    // The partial responses are discarded
+    let (processed_msg_send, _processed_msg_recv) = channel();
+    stream_response(client, profile, messages, &[], &processed_msg_send).await
+}
+
+/// Lists the models that can be chosen for the given profile
+pub async fn list_models(client: &Client, profile: &Profile) -> Result<Vec<String>, String> {
+    match profile.provider {
+        Provider::OpenAi | Provider::Anthropic => list_api_models(client, profile).await,
+        Provider::Ollama => list_ollama_models(client, profile).await,
+        Provider::Azure => Err(format!(
+            "Azure deployments can't be listed, set `deployment` in the '{}' profile instead",
+            profile.name
+        )),
+    }
+}
+
+/// Lists the chat models from the `/models` endpoint, which OpenAI and Anthropic share
+async fn list_api_models(client: &Client, profile: &Profile) -> Result<Vec<String>, String> {
+    let request = match profile.provider {
+        Provider::Anthropic => client
+            .get(format!("{}/models?limit=1000", profile.base_url()))
+            .header("anthropic-version", ANTHROPIC_VERSION),
+        _ => client.get(format!("{}/models", profile.base_url())),
+    };
+
+    let mut response = match with_profile_headers(request, profile)?.send().await {
+        Ok(response) => response,
+        Err(e) => return Err(format!("Could not get the list of models: {e:?}")),
+    };
+
+    forget_rejected_key(profile, &response);
+
+    let body = response
+        .body_string()
+        .await
+        .map_err(|e| format!("Could not read the list of models: {e}"))?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Could not get the list of models ({}): {}",
+            response.status(),
+            parse_error(&body)
+        ));
+    }
+
+    let models = serde_json::from_str::<ModelsResponse>(&body)
+        .map_err(|e| format!("Could not parse the list of models: {e}"))?;
+
+    let mut models: Vec<String> = models
+        .data
+        .into_iter()
+        .map(|m| m.id)
+        .filter(|id| !NON_CHAT_MODELS.iter().any(|part| id.contains(part)))
+        .collect();
+    models.sort();
+
+    Ok(models)
+}
+
+/// Adds the authentication header and any extra headers of the profile to a request
+fn with_profile_headers(
+    mut request: RequestBuilder,
+    profile: &Profile,
+) -> Result<RequestBuilder, String> {
+    match (profile.auth(), profile.api_key()?) {
+        (AuthScheme::Bearer, Some(apikey)) => {
+            request = request.header("Authorization", format!("Bearer {apikey}"));
+        }
+        (AuthScheme::ApiKey, Some(apikey)) => {
+            request = request.header("api-key", apikey);
+        }
+        (AuthScheme::XApiKey, Some(apikey)) => {
+            request = request.header("x-api-key", apikey);
+        }
+        _ => {}
+    }
+
+    for (name, value) in &profile.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    Ok(request)
+}
+
+/// Forgets the key of the profile if the server rejected it, so a key from a command is fetched
+/// again for the next request, in case it has expired
+fn forget_rejected_key(profile: &Profile, response: &Response) {
+    if response.status() == StatusCode::Unauthorized {
+        profile.forget_key();
+    }
+}
+
+/// The text of a message for providers that have no fields for names or tool calls. The name
+/// is written before the text, and the tools that were called are described after it.
+fn flattened_text(m: &Message) -> String {
+    let mut text = match &m.name {
+        Some(name) => format!("{name}: {}", m.sent_content()),
+        None => m.sent_content().into_owned(),
+    };
+
+    for call in &m.tool_calls {
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+
+        text.push_str(&format!(
+            "[Called the {} tool with {}]",
+            call.function.name, call.function.arguments
+        ));
+    }
+
+    text
+}
+
+/// Creates the (empty) assistant message that a response is streamed into
+fn response_message(profile: &Profile) -> Message {
+    Message {
+        role: Role::Assistant,
+        content: String::new(),
+        metadata: MessageMetadata {
+            profile: Some(profile.name.to_owned()),
+            model: Some(profile.model().to_owned()),
+            created_at: Some(Local::now()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Adds a streamed fragment to the tool call at its index
+fn add_tool_call_delta(tool_calls: &mut Vec<ToolCall>, fragment: ToolCallDelta) {
+    if fragment.index >= tool_calls.len() {
+        tool_calls.resize_with(fragment.index + 1, || ToolCall {
+            id: String::new(),
+            kind: "function".to_string(),
+            function: FunctionCall::default(),
+        });
+    }
+
+    let call = &mut tool_calls[fragment.index];
+
+    if let Some(id) = fragment.id {
+        call.id = id;
+    }
+
+    if let Some(name) = fragment.function.name {
+        call.function.name.push_str(&name);
+    }
+
+    if let Some(arguments) = fragment.function.arguments {
+        call.function.arguments.push_str(&arguments);
+    }
+}
+
+/// Sends the text that was just added to a streamed response to the UI. The first update
+/// sends the whole message so the UI knows who it's from, later updates only send the new text.
+fn send_update(processed_msg_send: &Sender<ProcessedMessage>, message: &Message, delta: &str) {
+    if delta.is_empty() {
+        return;
+    }
+
+    let update = if message.content.len() == delta.len() {
+        ProcessedMessage::ChatMessage(Ok(Box::new(message.to_owned())))
+    } else {
+        ProcessedMessage::ResponseDelta(delta.to_owned())
+    };
+
+    processed_msg_send.send(update).unwrap();
+}
+
+async fn stream_chatgpt_response(
+    client: &Client,
+    profile: &Profile,
+    messages: &[Message],
+    tools: &[Value],
     processed_msg_send: &Sender<ProcessedMessage>,
 ) -> Result<Message, String> {
     let body = ApiRequest {
-        model: "gpt-3.5-turbo",
-        messages: messages.clone(),
+        model: profile.model(),
+        messages: messages
+            .iter()
+            .map(|m| RequestMessage {
+                role: &m.role,
+                content: m.into(),
+                name: m.name.as_deref(),
+                tool_calls: &m.tool_calls,
+                tool_call_id: m.tool_call_id.as_deref(),
+            })
+            .collect(),
         stream: true,
+        max_tokens: profile.max_tokens,
+        // Older Azure API versions reject this option, so Azure responses have no usage
+        stream_options: match profile.provider {
+            Provider::Azure => None,
+            _ => Some(StreamOptions {
+                include_usage: true,
+            }),
+        },
+        tools,
     };
 
-    // Fetch the ChatGPT response
-    let res = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {apikey}"))
+    let request = client
+        .post(chat_completions_url(profile))
         .body_json(&body)
-        .unwrap()
-        .send()
-        .await;
+        .unwrap();
+
+    // Fetch the ChatGPT response
+    let res = with_profile_headers(request, profile)?.send().await;
 
     let mut response = match res {
         Ok(response) => response,
         Err(e) => return Err(format!("Could not get a response from ChatGPT: {e:?}")),
     };
 
-    let mut message = Message {
-        role: Role::Assistant,
-        content: String::new(),
-    };
+    forget_rejected_key(profile, &response);
 
-    loop {
-        let buf = response.fill_buf().await.unwrap();
-        let buffer_length = buf.len();
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.body_string().await.unwrap_or_default();
 
-        let data = str::from_utf8(buf).unwrap();
-        let deltas: Vec<Value> = data
-            .lines()
-            .filter_map(|d| serde_json::from_str(&d.chars().skip(6).collect::<String>()).ok())
-            .collect();
+        return Err(format!(
+            "ChatGPT returned an error ({status}): {}",
+            parse_error(&body)
+        ));
+    }
+
+    let mut message = response_message(profile);
+    read_chatgpt_stream(response, &mut message, processed_msg_send).await?;
+
+    processed_msg_send
+        .send(ProcessedMessage::ChatMessage(Ok(Box::new(
+            message.to_owned(),
+        ))))
+        .unwrap();
+
+    Ok(message)
+}
+
+/// Reads the server-sent events of a streamed response line by line, adding each chunk to the
+/// message and sending the new text to the UI
+async fn read_chatgpt_stream(
+    stream: impl AsyncBufRead + Unpin,
+    message: &mut Message,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<(), String> {
+    let mut lines = stream.lines();
+
+    while let Some(line) = lines.next().await {
+        let line = line.map_err(|e| format!("Could not read the response from ChatGPT: {e}"))?;
+
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => continue,
+        };
+
+        if data == "[DONE]" {
+            break;
+        }
+
+        let Ok(chunk) = serde_json::from_str::<StreamChunk>(data) else {
+            continue;
+        };
 
-        // very cool
-        for data in deltas {
-            let delta_value = data
-                .as_object()
-                .unwrap()
-                .get("choices")
-                .unwrap()
-                .as_array()
-                .unwrap()
-                .last()
-                .unwrap()
-                .as_object()
-                .unwrap()
-                .get("delta")
-                .unwrap()
-                .as_object()
-                .unwrap();
-
-            if delta_value.contains_key("content") {
-                message
-                    .content
-                    .push_str(delta_value.get("content").unwrap().as_str().unwrap());
+        if let Some(usage) = chunk.usage {
+            message.metadata.usage = Some(usage);
+        }
+
+        let mut delta = String::new();
+
+        // Azure sends chunks without any choices (e.g. for prompt filter results), so we can't
+        // assume that there is always one
+        for choice in chunk.choices {
+            if let Some(content) = choice.delta.content {
+                delta.push_str(&content);
+            }
+
+            for fragment in choice.delta.tool_calls.unwrap_or_default() {
+                add_tool_call_delta(&mut message.tool_calls, fragment);
+            }
+
+            if choice.finish_reason.as_deref() == Some("content_filter") {
+                message.metadata.content_filter = Some(
+                    choice
+                        .content_filter_results
+                        .as_ref()
+                        .map(filtered_categories)
+                        .unwrap_or_default(),
+                );
             }
         }
 
-        processed_msg_send
-            .send(ProcessedMessage::ChatMessage(Ok(message.to_owned())))
-            .unwrap();
+        message.content.push_str(&delta);
+        send_update(processed_msg_send, message, &delta);
+    }
+
+    Ok(())
+}
 
-        response.consume_unpin(buffer_length);
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::io::{BufReader, Cursor};
 
-        if buffer_length == 0 {
-            break;
+    use super::*;
+
+    /// A response that writes some text and then calls two tools, as OpenAI streams it
+    const TOOL_CALL_STREAM: &str = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"Let me "},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"check."},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"read_file","arguments":""}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"pa"}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_b","type":"function","function":{"name":"list_files","arguments":"{}"}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"th\": \"src/main.rs\"}"}}]},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[],"usage":{"prompt_tokens":52,"completion_tokens":31,"total_tokens":83}}
+
+data: [DONE]
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"ignored"},"finish_reason":null}],"usage":null}
+"#;
+
+    fn fragment(json: &str) -> ToolCallDelta {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Reads a stream through a buffer of `capacity` bytes, which splits most lines across
+    /// reads. Returns the response and the updates sent to the UI.
+    fn read_stream(stream: &str, capacity: usize) -> (Message, Vec<ProcessedMessage>) {
+        let (processed_msg_send, processed_msg_recv) = channel();
+        let mut message = Message::default();
+        let reader = BufReader::with_capacity(capacity, Cursor::new(stream.as_bytes().to_vec()));
+
+        block_on(read_chatgpt_stream(
+            reader,
+            &mut message,
+            &processed_msg_send,
+        ))
+        .unwrap();
+
+        (message, processed_msg_recv.try_iter().collect())
+    }
+
+    #[test]
+    fn joins_the_arguments_of_a_tool_call() {
+        let mut tool_calls = vec![];
+
+        add_tool_call_delta(
+            &mut tool_calls,
+            fragment(r#"{"index":0,"id":"call_a","function":{"name":"read_file","arguments":""}}"#),
+        );
+        add_tool_call_delta(
+            &mut tool_calls,
+            fragment(r#"{"index":0,"function":{"arguments":"{\"path\":"}}"#),
+        );
+        add_tool_call_delta(
+            &mut tool_calls,
+            fragment(r#"{"index":0,"function":{"arguments":" \"a.rs\"}"}}"#),
+        );
+
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_a");
+        assert_eq!(tool_calls[0].kind, "function");
+        assert_eq!(tool_calls[0].function.name, "read_file");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"path": "a.rs"}"#);
+    }
+
+    #[test]
+    fn keeps_interleaved_tool_calls_apart() {
+        let mut tool_calls = vec![];
+
+        for json in [
+            r#"{"index":1,"id":"call_b","function":{"name":"list_files","arguments":"{"}}"#,
+            r#"{"index":0,"id":"call_a","function":{"name":"read_file","arguments":"{\"a\""}}"#,
+            r#"{"index":1,"function":{"arguments":"}"}}"#,
+            r#"{"index":0,"function":{"arguments":":1}"}}"#,
+        ] {
+            add_tool_call_delta(&mut tool_calls, fragment(json));
         }
+
+        let calls: Vec<_> = tool_calls
+            .iter()
+            .map(|call| (call.id.as_str(), call.function.arguments.as_str()))
+            .collect();
+        assert_eq!(calls, [("call_a", r#"{"a":1}"#), ("call_b", "{}")]);
     }
 
-    Ok(message)
+    #[test]
+    fn reads_a_response_that_calls_tools() {
+        let (message, _) = read_stream(TOOL_CALL_STREAM, 8 * 1024);
+
+        assert_eq!(message.content, "Let me check.");
+
+        let calls: Vec<_> = message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                (
+                    call.id.as_str(),
+                    call.function.name.as_str(),
+                    call.function.arguments.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                ("call_a", "read_file", r#"{"path": "src/main.rs"}"#),
+                ("call_b", "list_files", "{}"),
+            ]
+        );
+
+        let usage = message.metadata.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (52, 31));
+    }
+
+    #[test]
+    fn reads_lines_split_across_reads() {
+        let (whole, _) = read_stream(TOOL_CALL_STREAM, 8 * 1024);
+
+        for capacity in [1, 7, 64] {
+            let (message, _) = read_stream(TOOL_CALL_STREAM, capacity);
+
+            assert_eq!(message.content, whole.content);
+            assert_eq!(message.tool_calls.len(), 2);
+            assert_eq!(
+                message.tool_calls[0].function.arguments,
+                whole.tool_calls[0].function.arguments
+            );
+        }
+    }
+
+    #[test]
+    fn sends_the_message_and_then_the_new_text() {
+        let (_, updates) = read_stream(TOOL_CALL_STREAM, 7);
+
+        assert_eq!(updates.len(), 2);
+        assert!(
+            matches!(&updates[0], ProcessedMessage::ChatMessage(Ok(m)) if m.content == "Let me ")
+        );
+        assert!(matches!(&updates[1], ProcessedMessage::ResponseDelta(d) if d == "check."));
+    }
+
+    #[test]
+    fn reads_responses_stopped_by_the_content_filter() {
+        let stream = r#"data: {"choices":[],"prompt_filter_results":[{"prompt_index":0,"content_filter_results":{}}]}
+
+data: {"choices":[{"index":0,"delta":{"content":"Once upon"},"finish_reason":null}]}
+
+data: {"choices":[{"index":0,"delta":{},"finish_reason":"content_filter","content_filter_results":{"hate":{"filtered":false,"severity":"safe"},"violence":{"filtered":true,"severity":"medium"}}}]}
+
+data: [DONE]
+"#;
+
+        let (message, _) = read_stream(stream, 64);
+
+        assert_eq!(message.content, "Once upon");
+        assert_eq!(
+            message.metadata.content_filter,
+            Some(vec!["violence (medium)".to_string()])
+        );
+    }
+
+    #[test]
+    fn parses_openai_errors() {
+        let body = r#"{"error":{"message":"Incorrect API key provided: sk-abc. You can find your API key at https://platform.openai.com/account/api-keys.","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#;
+
+        assert!(parse_error(body).starts_with("Incorrect API key provided: sk-abc."));
+    }
+
+    #[test]
+    fn parses_content_filter_errors() {
+        let body = r#"{"error":{"message":"The response was filtered due to the prompt triggering Azure OpenAI's content management policy.","type":null,"param":"prompt","code":"content_filter","status":400,"innererror":{"code":"ResponsibleAIPolicyViolation","content_filter_result":{"hate":{"filtered":false,"severity":"safe"},"jailbreak":{"filtered":false,"detected":false},"self_harm":{"filtered":true,"severity":"high"},"sexual":{"filtered":false,"severity":"safe"},"violence":{"filtered":true,"severity":"medium"}}}}}"#;
+
+        assert_eq!(
+            parse_error(body),
+            "The prompt was blocked by the content filter: self_harm (high), violence (medium)\n\n\
+             The response was filtered due to the prompt triggering Azure OpenAI's content \
+             management policy."
+        );
+    }
+
+    #[test]
+    fn parses_unwrapped_azure_errors() {
+        let body = r#"{ "statusCode": 401, "message": "Access denied due to invalid subscription key or wrong API endpoint." }"#;
+
+        assert_eq!(
+            parse_error(body),
+            "Access denied due to invalid subscription key or wrong API endpoint."
+        );
+    }
+
+    #[test]
+    fn keeps_bodies_that_are_not_errors() {
+        assert_eq!(
+            parse_error("upstream request timeout"),
+            "upstream request timeout"
+        );
+        assert_eq!(
+            parse_error(r#"{"detail":"Not Found"}"#),
+            r#"{"detail":"Not Found"}"#
+        );
+    }
 }
@@ -0,0 +1,381 @@
+use std::sync::mpsc::Sender;
+
+use futures::{AsyncBufRead, AsyncBufReadExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use surf::Client;
+
+use super::{
+    flattened_text, forget_rejected_key, response_message, send_update, with_profile_headers,
+};
+use crate::config::Profile;
+use crate::message::{ContentPart, Message, Role, Usage};
+use crate::ProcessedMessage;
+
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Anthropic requires `max_tokens` in every request
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Serialize)]
+struct AnthropicMessage<'a> {
+    role: &'a Role,
+    content: Vec<RequestBlock<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestBlock<'a> {
+    Text { text: String },
+    Image { source: ImageSource<'a> },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ImageSource<'a> {
+    Base64 { media_type: &'a str, data: &'a str },
+    Url { url: &'a str },
+}
+
+/// Converts a message to content blocks. Anthropic rejects empty text blocks, so they are
+/// left out.
+fn content_blocks(m: &Message) -> Vec<RequestBlock<'_>> {
+    let text = RequestBlock::Text {
+        text: flattened_text(m),
+    };
+
+    let parts = m.parts.iter().map(|part| match part {
+        ContentPart::Text { text } => RequestBlock::Text {
+            text: text.to_owned(),
+        },
+        ContentPart::ImageUrl { image_url } => RequestBlock::Image {
+            source: match image_url.inline_data() {
+                Some((media_type, data)) => ImageSource::Base64 { media_type, data },
+                None => ImageSource::Url {
+                    url: &image_url.url,
+                },
+            },
+        },
+    });
+
+    std::iter::once(text)
+        .chain(parts)
+        .filter(|block| !matches!(block, RequestBlock::Text { text } if text.is_empty()))
+        .collect()
+}
+
+/// Converts the messages that aren't system prompts. Anthropic rejects messages without any
+/// content, so they are left out, and the turns around them are joined by the API.
+fn anthropic_messages(messages: &[Message]) -> Vec<AnthropicMessage<'_>> {
+    messages
+        .iter()
+        .filter(|m| !matches!(m.role, Role::System))
+        .map(|m| AnthropicMessage {
+            // Tools are only offered to OpenAI models, so the results of tools that were
+            // called before the profile was switched are sent as user messages
+            role: match m.role {
+                Role::Tool => &Role::User,
+                _ => &m.role,
+            },
+            content: content_blocks(m),
+        })
+        .filter(|m| !m.content.is_empty())
+        .collect()
+}
+
+#[derive(Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage<'a>>,
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    MessageDelta {
+        usage: AnthropicUsage,
+    },
+    ContentBlockStart {
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        delta: ContentBlock,
+    },
+    MessageStop,
+    Error {
+        error: AnthropicError,
+    },
+    /// `ping`, `content_block_stop` and any new event types
+    #[serde(other)]
+    Other,
+}
+
+/// Both content blocks and their deltas, which only differ in their type tag
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    usage: AnthropicUsage,
+}
+
+/// The usage so far. `message_start` has the input tokens, and `message_delta` has the
+/// final output tokens.
+#[derive(Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: AnthropicError,
+}
+
+#[derive(Deserialize)]
+struct AnthropicError {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+impl AnthropicError {
+    fn describe(&self) -> String {
+        format!("{} ({})", self.message, self.error_type)
+    }
+}
+
+pub async fn stream_anthropic_response(
+    client: &Client,
+    profile: &Profile,
+    messages: &[Message],
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<Message, String> {
+    // The Messages API has no system role, the system prompt is a top-level field instead
+    let system_prompts: Vec<&str> = messages
+        .iter()
+        .filter(|m| matches!(m.role, Role::System))
+        .map(|m| m.content.as_str())
+        .collect();
+
+    let body = AnthropicRequest {
+        model: profile.model(),
+        max_tokens: profile.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        system: (!system_prompts.is_empty()).then(|| system_prompts.join("\n\n")),
+        messages: anthropic_messages(messages),
+        stream: true,
+    };
+
+    let request = client
+        .post(format!("{}/messages", profile.base_url()))
+        .header("anthropic-version", ANTHROPIC_VERSION)
+        .body_json(&body)
+        .unwrap();
+
+    let mut response = match with_profile_headers(request, profile)?.send().await {
+        Ok(response) => response,
+        Err(e) => return Err(format!("Could not get a response from Anthropic: {e:?}")),
+    };
+
+    forget_rejected_key(profile, &response);
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.body_string().await.unwrap_or_default();
+
+        let error = match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(response) => response.error.describe(),
+            Err(_) => body,
+        };
+
+        return Err(format!("Anthropic returned an error ({status}): {error}"));
+    }
+
+    let mut message = response_message(profile);
+    read_anthropic_stream(response, &mut message, processed_msg_send).await?;
+
+    processed_msg_send
+        .send(ProcessedMessage::ChatMessage(Ok(Box::new(
+            message.to_owned(),
+        ))))
+        .unwrap();
+
+    Ok(message)
+}
+
+/// Reads the events of a streamed response, adding the text of its content blocks to the
+/// message and sending it to the UI
+async fn read_anthropic_stream(
+    stream: impl AsyncBufRead + Unpin,
+    message: &mut Message,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<(), String> {
+    let mut lines = stream.lines();
+
+    while let Some(line) = lines.next().await {
+        let line = line.map_err(|e| format!("Could not read the response from Anthropic: {e}"))?;
+
+        // We only need the data lines, since each event's data also contains its type
+        let event = match line.strip_prefix("data:") {
+            Some(data) => match serde_json::from_str::<StreamEvent>(data.trim()) {
+                Ok(event) => event,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        match event {
+            StreamEvent::MessageStart { message: start } => {
+                message.metadata.usage = Some(Usage {
+                    prompt_tokens: start.usage.input_tokens,
+                    completion_tokens: start.usage.output_tokens,
+                });
+            }
+            StreamEvent::MessageDelta { usage } => {
+                let total = message.metadata.usage.get_or_insert_with(Usage::default);
+                total.completion_tokens = usage.output_tokens;
+            }
+            StreamEvent::ContentBlockStart {
+                content_block: ContentBlock::Text { text },
+            }
+            | StreamEvent::ContentBlockDelta {
+                delta: ContentBlock::TextDelta { text },
+            } => {
+                message.content.push_str(&text);
+                send_update(processed_msg_send, message, &text);
+            }
+            StreamEvent::MessageStop => break,
+            StreamEvent::Error { error } => {
+                return Err(format!("Anthropic returned an error: {}", error.describe()))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use futures::executor::block_on;
+    use futures::io::{BufReader, Cursor};
+
+    use super::*;
+
+    /// A short response, as Anthropic streams it
+    const STREAM: &str = r#"event: message_start
+data: {"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20241022","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":25,"output_tokens":1}}}
+
+event: content_block_start
+data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}
+
+event: ping
+data: {"type": "ping"}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":", world!"}}
+
+event: content_block_stop
+data: {"type":"content_block_stop","index":0}
+
+event: message_delta
+data: {"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":15}}
+
+event: message_stop
+data: {"type":"message_stop"}
+
+"#;
+
+    fn read_stream(stream: &str) -> (Result<Message, String>, Vec<ProcessedMessage>) {
+        let (processed_msg_send, processed_msg_recv) = channel();
+        let mut message = Message::default();
+        let reader = BufReader::with_capacity(16, Cursor::new(stream.as_bytes().to_vec()));
+
+        let result = block_on(read_anthropic_stream(
+            reader,
+            &mut message,
+            &processed_msg_send,
+        ))
+        .map(|_| message);
+
+        (result, processed_msg_recv.try_iter().collect())
+    }
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reads_text_and_usage() {
+        let (message, updates) = read_stream(STREAM);
+        let message = message.unwrap();
+
+        assert_eq!(message.content, "Hello, world!");
+
+        let usage = message.metadata.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (25, 15));
+
+        assert_eq!(updates.len(), 2);
+        assert!(matches!(&updates[1], ProcessedMessage::ResponseDelta(d) if d == ", world!"));
+    }
+
+    #[test]
+    fn reports_errors_in_the_stream() {
+        let stream = r#"event: error
+data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}
+"#;
+
+        let (message, _) = read_stream(stream);
+        assert_eq!(
+            message.err().unwrap(),
+            "Anthropic returned an error: Overloaded (overloaded_error)"
+        );
+    }
+
+    #[test]
+    fn leaves_out_empty_messages() {
+        let messages = [
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+            // A response that was stopped before any text arrived
+            message(Role::Assistant, ""),
+            message(Role::User, "Hello?"),
+        ];
+
+        let request = serde_json::to_value(anthropic_messages(&messages)).unwrap();
+        assert_eq!(
+            request,
+            serde_json::json!([
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {"role": "user", "content": [{"type": "text", "text": "Hello?"}]},
+            ])
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::sync::mpsc::Sender;
+
+use futures::{AsyncBufRead, AsyncBufReadExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use surf::Client;
+
+use super::{
+    flattened_text, forget_rejected_key, response_message, send_update, with_profile_headers,
+};
+use crate::config::Profile;
+use crate::message::{ContentPart, Message, Role, Usage};
+use crate::ProcessedMessage;
+
+#[derive(Serialize)]
+struct OllamaMessage<'a> {
+    role: &'a Role,
+    content: String,
+    /// Base64 encoded images
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<&'a str>,
+}
+
+/// Converts a message for Ollama, which only accepts images as base64 data
+fn ollama_message(m: &Message) -> Result<OllamaMessage<'_>, String> {
+    let mut content = flattened_text(m);
+    let mut images = vec![];
+
+    for part in &m.parts {
+        match part {
+            ContentPart::Text { text } => {
+                content.push_str("\n\n");
+                content.push_str(text);
+            }
+            ContentPart::ImageUrl { image_url } => match image_url.inline_data() {
+                Some((_, data)) => images.push(data),
+                None => {
+                    return Err(format!(
+                        "Ollama can only be sent local images, not {}",
+                        image_url.url
+                    ))
+                }
+            },
+        }
+    }
+
+    Ok(OllamaMessage {
+        role: &m.role,
+        content,
+        images,
+    })
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    num_predict: u32,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest<'a> {
+    model: &'a str,
+    messages: Vec<OllamaMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
+}
+
+/// A single line of the streamed response. Unlike the other providers, Ollama streams
+/// newline-delimited JSON objects rather than server-sent events.
+#[derive(Deserialize)]
+struct ChatChunk {
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+    /// Token counts, only sent in the last chunk
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ChunkMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    name: String,
+}
+
+pub async fn stream_ollama_response(
+    client: &Client,
+    profile: &Profile,
+    messages: &[Message],
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<Message, String> {
+    let body = OllamaRequest {
+        model: profile.model(),
+        messages: messages
+            .iter()
+            .map(ollama_message)
+            .collect::<Result<_, _>>()?,
+        stream: true,
+        options: profile
+            .max_tokens
+            .map(|num_predict| OllamaOptions { num_predict }),
+    };
+
+    let request = client
+        .post(format!("{}/api/chat", profile.base_url()))
+        .body_json(&body)
+        .unwrap();
+
+    let mut response = match with_profile_headers(request, profile)?.send().await {
+        Ok(response) => response,
+        Err(e) => return Err(format!("Could not get a response from Ollama: {e:?}")),
+    };
+
+    forget_rejected_key(profile, &response);
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.body_string().await.unwrap_or_default();
+
+        return Err(format!(
+            "Ollama returned an error ({status}): {}",
+            parse_error(&body)
+        ));
+    }
+
+    let mut message = response_message(profile);
+    read_ollama_stream(response, &mut message, processed_msg_send).await?;
+
+    processed_msg_send
+        .send(ProcessedMessage::ChatMessage(Ok(Box::new(
+            message.to_owned(),
+        ))))
+        .unwrap();
+
+    Ok(message)
+}
+
+/// Reads the JSON objects of a streamed response, one per line, adding their text to the
+/// message and sending it to the UI. The last object has the token counts.
+async fn read_ollama_stream(
+    stream: impl AsyncBufRead + Unpin,
+    message: &mut Message,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Result<(), String> {
+    let mut lines = stream.lines();
+
+    while let Some(line) = lines.next().await {
+        let line = line.map_err(|e| format!("Could not read the response from Ollama: {e}"))?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let chunk = serde_json::from_str::<ChatChunk>(&line)
+            .map_err(|e| format!("Could not parse the response from Ollama: {e}"))?;
+
+        if let Some(error) = chunk.error {
+            return Err(format!("Ollama returned an error: {error}"));
+        }
+
+        if let Some(chunk_message) = chunk.message {
+            message.content.push_str(&chunk_message.content);
+            send_update(processed_msg_send, message, &chunk_message.content);
+        }
+
+        if chunk.done {
+            message.metadata.usage = Some(Usage {
+                prompt_tokens: chunk.prompt_eval_count.unwrap_or_default(),
+                completion_tokens: chunk.eval_count.unwrap_or_default(),
+            });
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the models that are installed on the Ollama server
+pub async fn list_ollama_models(client: &Client, profile: &Profile) -> Result<Vec<String>, String> {
+    let request = client.get(format!("{}/api/tags", profile.base_url()));
+
+    let mut response = match with_profile_headers(request, profile)?.send().await {
+        Ok(response) => response,
+        Err(e) => return Err(format!("Could not connect to Ollama: {e:?}")),
+    };
+
+    forget_rejected_key(profile, &response);
+
+    let body = response
+        .body_string()
+        .await
+        .map_err(|e| format!("Could not read the model list from Ollama: {e}"))?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Ollama returned an error ({}): {}",
+            response.status(),
+            parse_error(&body)
+        ));
+    }
+
+    let tags = serde_json::from_str::<TagsResponse>(&body)
+        .map_err(|e| format!("Could not parse the model list from Ollama: {e}"))?;
+
+    let mut models: Vec<String> = tags.models.into_iter().map(|m| m.name).collect();
+    models.sort();
+
+    Ok(models)
+}
+
+fn parse_error(body: &str) -> String {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(response) => response.error,
+        Err(_) => body.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use futures::executor::block_on;
+    use futures::io::{BufReader, Cursor};
+
+    use super::*;
+
+    /// A short response, as Ollama streams it
+    const STREAM: &str = r#"{"model":"llama3.2","created_at":"2024-11-05T10:15:02.11Z","message":{"role":"assistant","content":"Hello"},"done":false}
+{"model":"llama3.2","created_at":"2024-11-05T10:15:02.15Z","message":{"role":"assistant","content":", world!"},"done":false}
+{"model":"llama3.2","created_at":"2024-11-05T10:15:02.19Z","message":{"role":"assistant","content":""},"done_reason":"stop","done":true,"total_duration":412937125,"load_duration":20416,"prompt_eval_count":26,"prompt_eval_duration":120000000,"eval_count":4,"eval_duration":85000000}
+"#;
+
+    fn read_stream(stream: &str) -> (Result<Message, String>, Vec<ProcessedMessage>) {
+        let (processed_msg_send, processed_msg_recv) = channel();
+        let mut message = Message::default();
+        let reader = BufReader::with_capacity(16, Cursor::new(stream.as_bytes().to_vec()));
+
+        let result = block_on(read_ollama_stream(
+            reader,
+            &mut message,
+            &processed_msg_send,
+        ))
+        .map(|_| message);
+
+        (result, processed_msg_recv.try_iter().collect())
+    }
+
+    #[test]
+    fn reads_text_and_usage() {
+        let (message, updates) = read_stream(STREAM);
+        let message = message.unwrap();
+
+        assert_eq!(message.content, "Hello, world!");
+
+        let usage = message.metadata.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (26, 4));
+
+        assert_eq!(updates.len(), 2);
+        assert!(
+            matches!(&updates[0], ProcessedMessage::ChatMessage(Ok(m)) if m.content == "Hello")
+        );
+    }
+
+    #[test]
+    fn reports_errors_in_the_stream() {
+        let (message, _) = read_stream("{\"error\":\"model 'llama9' not found\"}\n");
+        assert_eq!(
+            message.err().unwrap(),
+            "Ollama returned an error: model 'llama9' not found"
+        );
+
+        let (message, _) = read_stream("not json\n");
+        assert!(message
+            .err()
+            .unwrap()
+            .starts_with("Could not parse the response from Ollama"));
+    }
+
+    #[test]
+    fn parses_error_bodies() {
+        assert_eq!(
+            parse_error(r#"{"error":"model 'llama9' not found, try pulling it first"}"#),
+            "model 'llama9' not found, try pulling it first"
+        );
+        assert_eq!(parse_error("Bad Gateway"), "Bad Gateway");
+    }
+}
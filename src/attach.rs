@@ -0,0 +1,438 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use syntect::parsing::SyntaxSet;
+
+use crate::message::{Attachment, AttachmentKind, ContentPart, ImageUrl};
+
+/// The largest file that can be attached, in bytes
+const MAX_ATTACHMENT_SIZE: u64 = 256 * 1024;
+
+/// How much of the start of a file is checked for null bytes, like git does
+const BINARY_CHECK_LENGTH: usize = 8000;
+
+/// The largest image that can be sent, in bytes
+const MAX_IMAGE_SIZE: u64 = 20 * 1024 * 1024;
+
+/// The most output of a command that is attached, in characters
+const MAX_OUTPUT_LENGTH: usize = 32 * 1024;
+
+/// A file, or the output of a command, that is added to the next message
+#[derive(Clone)]
+pub struct PendingAttachment {
+    /// The path as it was typed, or the command that was run
+    pub path: String,
+    pub kind: AttachmentKind,
+    /// The language tag of the code block, inferred from the file name and first line
+    pub language: String,
+    pub content: String,
+}
+
+impl PendingAttachment {
+    /// What is kept in the metadata of the message, to show the attachment
+    pub fn info(&self) -> Attachment {
+        Attachment {
+            path: self.path.to_owned(),
+            lines: self.content.lines().count(),
+            kind: self.kind,
+        }
+    }
+}
+
+/// Resolves `~` to the home directory
+fn expand_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Reads a text file to attach it to a message
+pub fn read_attachment(path: &str, syntax_set: &SyntaxSet) -> Result<PendingAttachment, String> {
+    let file = expand_path(path);
+
+    let size = fs::metadata(&file)
+        .map_err(|e| format!("Could not attach {path}: {e}"))?
+        .len();
+
+    if size > MAX_ATTACHMENT_SIZE {
+        return Err(format!(
+            "Could not attach {path}: it is {} KB, and at most {} KB can be attached",
+            size.div_ceil(1024),
+            MAX_ATTACHMENT_SIZE / 1024
+        ));
+    }
+
+    let bytes = fs::read(&file).map_err(|e| format!("Could not attach {path}: {e}"))?;
+
+    let binary = bytes.iter().take(BINARY_CHECK_LENGTH).any(|&b| b == 0);
+    let content = match (binary, String::from_utf8(bytes)) {
+        (false, Ok(content)) => content,
+        _ => return Err(format!("Could not attach {path}: it is not a text file")),
+    };
+
+    let language = syntax_set
+        .find_syntax_for_file(&file)
+        .ok()
+        .flatten()
+        .and_then(|syntax| syntax.file_extensions.first().cloned())
+        .unwrap_or_default();
+
+    Ok(PendingAttachment {
+        path: path.to_owned(),
+        kind: AttachmentKind::File,
+        language,
+        content,
+    })
+}
+
+/// Reads an image to send with a message. Web addresses are sent as they are, and local files
+/// are encoded in a `data:` URL.
+pub fn read_image(source: &str) -> Result<ContentPart, String> {
+    if source.starts_with("https://") || source.starts_with("http://") {
+        return Ok(ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: source.to_owned(),
+                detail: None,
+            },
+        });
+    }
+
+    let file = expand_path(source);
+
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let media_type = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => {
+            return Err(format!(
+                "Could not add {source}: only PNG, JPEG, GIF and WebP images can be sent"
+            ))
+        }
+    };
+
+    let size = fs::metadata(&file)
+        .map_err(|e| format!("Could not add {source}: {e}"))?
+        .len();
+
+    if size > MAX_IMAGE_SIZE {
+        return Err(format!(
+            "Could not add {source}: it is {} MB, and at most {} MB can be sent",
+            size.div_ceil(1024 * 1024),
+            MAX_IMAGE_SIZE / 1024 / 1024
+        ));
+    }
+
+    let bytes = fs::read(&file).map_err(|e| format!("Could not add {source}: {e}"))?;
+
+    Ok(ContentPart::ImageUrl {
+        image_url: ImageUrl {
+            url: format!("data:{media_type};base64,{}", base64(&bytes)),
+            detail: None,
+        },
+    })
+}
+
+/// Encodes bytes in base64, with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+
+        // Each byte of the chunk is spread over one more character, the rest is padding
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Describes how a process ended
+pub fn exit_status(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("[exit status {code}]"),
+        None => "[stopped by a signal]".to_string(),
+    }
+}
+
+/// Combines the output of a process, followed by how it ended
+pub fn output_text(stdout: &[u8], stderr: &[u8], status: &str) -> String {
+    let mut content = String::from_utf8_lossy(stdout).to_string();
+    content.push_str(&String::from_utf8_lossy(stderr));
+
+    // Keep the end of long output, since that is where errors usually are
+    let length = content.chars().count();
+    if length > MAX_OUTPUT_LENGTH {
+        let end: String = content.chars().skip(length - MAX_OUTPUT_LENGTH).collect();
+        content = format!(
+            "[{} characters left out]\n{end}",
+            length - MAX_OUTPUT_LENGTH
+        );
+    }
+
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+
+    content.push_str(status);
+    content
+}
+
+/// The files mentioned with `@path` in a message. Mentions of paths that aren't files are
+/// left alone, since they may be meant for the model.
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut paths: Vec<String> = vec![];
+
+    for word in text.split_whitespace() {
+        let Some(path) = word.strip_prefix('@') else {
+            continue;
+        };
+
+        // Punctuation after a mention isn't part of the path
+        let path = path.trim_end_matches([',', ';', ':', '!', '?', ')']);
+
+        if expand_path(path).is_file() && !paths.iter().any(|p| p == path) {
+            paths.push(path.to_owned());
+        }
+    }
+
+    paths
+}
+
+/// Writes the contents of the attachments that are sent after the text of a message, each in a
+/// fenced code block
+pub fn attach(attachments: &[PendingAttachment]) -> String {
+    let mut content = String::new();
+
+    for attachment in attachments {
+        let fence = fence(&attachment.content);
+
+        content.push_str(&attachment.info().header());
+        content.push_str(&format!(
+            "{fence}{}\n{}\n{fence}",
+            attachment.language,
+            attachment.content.trim_end_matches('\n')
+        ));
+    }
+
+    content
+}
+
+/// A code fence for `content`, which must be longer than any run of backticks in it
+pub fn fence(content: &str) -> String {
+    let longest_run = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+
+    "`".repeat(longest_run.max(2) + 1)
+}
+
+/// Completes the path at the end of the input, if it follows `@`, `/attach` or `/image`.
+/// Completes as far as all matching files agree, and adds a `/` after directories.
+/// Returns `None` if the input doesn't end with a path.
+pub fn complete_input(input: &str) -> Option<String> {
+    let command = ["/attach ", "/image "]
+        .into_iter()
+        .find_map(|command| Some((command, input.strip_prefix(command)?)));
+
+    let (before, partial) = match command {
+        Some(command) => command,
+        None => {
+            let start = input.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+            let word = input[start..].strip_prefix('@')?;
+            (&input[..start + 1], word)
+        }
+    };
+
+    let (dir, name) = match partial.rfind('/') {
+        Some(i) => (&partial[..i + 1], &partial[i + 1..]),
+        None => ("", partial),
+    };
+
+    let Ok(entries) = fs::read_dir(match dir {
+        "" => PathBuf::from("."),
+        dir => expand_path(dir),
+    }) else {
+        return Some(input.to_owned());
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+
+            // Hidden files are only completed once their name is started
+            if !file_name.starts_with(name) || (file_name.starts_with('.') && name.is_empty()) {
+                return None;
+            }
+
+            Some(match entry.path().is_dir() {
+                true => format!("{file_name}/"),
+                false => file_name,
+            })
+        })
+        .collect();
+
+    candidates.sort();
+
+    let Some(first) = candidates.first() else {
+        return Some(input.to_owned());
+    };
+
+    let common = candidates
+        .iter()
+        .fold(first.to_owned(), |common, candidate| {
+            common
+                .chars()
+                .zip(candidate.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        });
+
+    Some(format!("{before}{dir}{common}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    /// Creates an empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("chatgpt-tui-attach-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn encodes_base64() {
+        // The test vectors of RFC 4648
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+
+        for (input, encoded) in cases {
+            assert_eq!(base64(input.as_bytes()), encoded);
+        }
+
+        assert_eq!(base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn attaches_text_files() {
+        let dir = test_dir("text");
+        let path = dir.join("main.rs");
+        fs::write(&path, "fn main() {}\n").unwrap();
+
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let attachment = read_attachment(path.to_str().unwrap(), &syntax_set).unwrap();
+
+        assert_eq!(attachment.content, "fn main() {}\n");
+        assert_eq!(attachment.language, "rs");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rejects_binary_files() {
+        let dir = test_dir("binary");
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+
+        // A null byte, as in most binary formats, and bytes that aren't UTF-8
+        for (name, bytes) in [
+            ("image.png", &b"\x89PNG\r\n\x1a\n\0\0"[..]),
+            ("latin1.txt", b"caf\xe9"),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, bytes).unwrap();
+
+            let error = read_attachment(path.to_str().unwrap(), &syntax_set)
+                .err()
+                .unwrap();
+            assert!(error.ends_with("it is not a text file"), "{error}");
+        }
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn finds_mentioned_files() {
+        let dir = test_dir("mentions");
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("main.rs"), "").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let text = format!(
+            "Compare @{dir}/notes.txt, @{dir}/notes.txt? and (see @{dir}/main.rs) \
+             with @{dir}/missing.txt, @{dir} and @someone"
+        );
+
+        assert_eq!(
+            mentions(&text),
+            [format!("{dir}/notes.txt"), format!("{dir}/main.rs")]
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn completes_paths() {
+        let dir = test_dir("complete");
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("notes-old.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        fs::create_dir(dir.join("src")).unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let complete = |input: String| complete_input(&input);
+
+        // As far as the matching files agree, and with a `/` after directories
+        assert_eq!(
+            complete(format!("@{dir}/no")),
+            Some(format!("@{dir}/notes"))
+        );
+        assert_eq!(
+            complete(format!("look at @{dir}/notes.")),
+            Some(format!("look at @{dir}/notes.txt"))
+        );
+        assert_eq!(
+            complete(format!("/attach {dir}/s")),
+            Some(format!("/attach {dir}/src/"))
+        );
+
+        // Hidden files only once their name is started
+        assert_eq!(complete(format!("@{dir}/")), Some(format!("@{dir}/")));
+        assert_eq!(
+            complete(format!("@{dir}/.h")),
+            Some(format!("@{dir}/.hidden"))
+        );
+
+        assert_eq!(complete(format!("@{dir}/zz")), Some(format!("@{dir}/zz")));
+        assert_eq!(complete("hello".to_string()), None);
+        fs::remove_dir_all(dir).ok();
+    }
+}
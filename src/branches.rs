@@ -0,0 +1,224 @@
+use cursive::event::Key;
+use cursive::view::{Nameable, Resizable, Scrollable};
+use cursive::views::{Dialog, EditView, LinearLayout, OnEventView, SelectView, TextView};
+use cursive::Cursive;
+
+use crate::message::{Message, Role};
+use crate::tokens::count_message_tokens;
+use crate::{show_error, update_messages, update_status, update_tab_bar, AppState, Request};
+
+/// Opens the navigator, which lists the messages of the active branch along with the number of
+/// alternatives to each of them
+pub fn open_branches(s: &mut Cursive) {
+    if s.find_name::<SelectView<usize>>("branches").is_some() {
+        return;
+    }
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(
+                    "Left/Right: switch branch, Enter: edit a message or regenerate a response",
+                ))
+                .child(
+                    OnEventView::new(
+                        SelectView::<usize>::new()
+                            .on_submit(|s, index| branch_from(s, *index))
+                            .with_name("branches"),
+                    )
+                    .on_event(Key::Left, |s| switch_sibling(s, -1))
+                    .on_event(Key::Right, |s| switch_sibling(s, 1))
+                    .scrollable(),
+                ),
+        )
+        .title("Branches")
+        .dismiss_button("Close"),
+    );
+
+    update_branches(s);
+}
+
+/// Lists the messages of the current tab's active branch in the navigator
+fn update_branches(s: &mut Cursive) {
+    let items = s
+        .with_user_data(|state: &mut AppState| {
+            let messages = &state.tab().messages;
+
+            (0..messages.len())
+                .map(|i| {
+                    let siblings = messages.siblings(i);
+                    let branch = match siblings.len() {
+                        1 => String::new(),
+                        count => {
+                            let position = siblings
+                                .iter()
+                                .position(|&node| node == messages.path()[i])
+                                .unwrap_or_default();
+
+                            format!("< {}/{count} >", position + 1)
+                        }
+                    };
+
+                    let m = &messages[i];
+                    let role = match m.role {
+                        Role::System if m.metadata.local => "Output",
+                        Role::System => "System",
+                        Role::User => "User",
+                        Role::Assistant => "Assistant",
+                        Role::Tool => "Tool",
+                    };
+                    let first_line = m.content.trim().lines().next().unwrap_or_default();
+
+                    (format!("{branch:>9} {role}: {first_line}"), i)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    s.call_on_name("branches", |view: &mut SelectView<usize>| {
+        let selected = view.selected_id();
+
+        view.clear();
+        view.add_all(items);
+
+        if let Some(selected) = selected {
+            view.set_selection(selected.min(view.len().saturating_sub(1)));
+        }
+    });
+}
+
+/// Switches the message selected in the navigator to its previous (or next) alternative
+fn switch_sibling(s: &mut Cursive, offset: isize) {
+    let selected = s
+        .call_on_name("branches", |view: &mut SelectView<usize>| view.selection())
+        .flatten();
+
+    let Some(index) = selected.map(|index| *index) else {
+        return;
+    };
+
+    let result = s
+        .with_user_data(|state: &mut AppState| {
+            let profile = state.profile();
+            let tab = state.tab_mut();
+
+            if tab.busy {
+                return Err("Wait for the response to finish before switching branches".to_string());
+            }
+
+            let siblings = tab.messages.siblings(index);
+            let position = siblings
+                .iter()
+                .position(|&node| node == tab.messages.path()[index])
+                .unwrap_or_default();
+            let node =
+                siblings[(position as isize + offset).rem_euclid(siblings.len() as isize) as usize];
+
+            tab.messages.switch(node);
+            tab.request_send.send(Request::Switch(node)).unwrap();
+
+            // Until the next request, assume that the whole branch fits in the context window
+            tab.context_start = 0;
+            tab.context_tokens = count_message_tokens(profile.model(), &tab.messages.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+    match result {
+        Ok(()) => {
+            update_messages(s);
+            update_branches(s);
+            update_tab_bar(s);
+            update_status(s);
+        }
+        Err(error) => show_error(s, error),
+    }
+}
+
+/// Starts a new branch at the message at `index` in the navigator: user messages are edited,
+/// and responses are regenerated
+fn branch_from(s: &mut Cursive, index: usize) {
+    // The message is sent again as it was, with only its text changed if it is edited
+    let (message, previous) = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab();
+            let messages = &tab.messages;
+            let previous = index
+                .checked_sub(1)
+                .map(|i| tab.resent_message(&messages[i]));
+
+            (tab.resent_message(&messages[index]), previous)
+        })
+        .unwrap();
+
+    match (&message.role, previous) {
+        (Role::User, _) => {
+            s.add_layer(
+                Dialog::around(
+                    EditView::new()
+                        .content(message.content.to_owned())
+                        .with_name("branch_edit")
+                        .min_width(60),
+                )
+                .title("Edit message")
+                .button("Send", move |s| {
+                    let text = s
+                        .call_on_name("branch_edit", |view: &mut EditView| view.get_content())
+                        .unwrap();
+
+                    if text.trim().is_empty() {
+                        return;
+                    }
+
+                    let mut message = message.to_owned();
+                    message.content = text.trim().to_owned();
+
+                    // Close the editor and the navigator
+                    s.pop_layer();
+                    s.pop_layer();
+                    send_branch(s, index, message);
+                })
+                .dismiss_button("Cancel"),
+            );
+        }
+        (Role::Assistant, Some(previous)) if matches!(previous.role, Role::User) => {
+            s.pop_layer();
+            send_branch(s, index - 1, previous);
+        }
+        _ => show_error(
+            s,
+            "Only user messages and their responses can be branched".to_string(),
+        ),
+    }
+}
+
+/// Sends `message` in place of the message at `index` of the current tab, on a new branch. The
+/// previous branch is kept and can be switched back to from the navigator.
+pub fn send_branch(s: &mut Cursive, index: usize, message: Message) {
+    let result = s
+        .with_user_data(|state: &mut AppState| {
+            if state.tab().busy {
+                return Err(
+                    "Wait for the response to finish before starting a new branch".to_string(),
+                );
+            }
+
+            let tab = state.tab_mut();
+            tab.busy = true;
+            tab.messages.rewind(index);
+            tab.context_start = tab.context_start.min(index);
+            tab.request_send
+                .send(Request::Edit(index, message))
+                .unwrap();
+            Ok(())
+        })
+        .unwrap();
+
+    match result {
+        Ok(()) => {
+            update_messages(s);
+            update_tab_bar(s);
+        }
+        Err(error) => show_error(s, error),
+    }
+}
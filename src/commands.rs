@@ -0,0 +1,93 @@
+pub enum Command {
+    /// Switch to the named profile, or pick one from a list if no name was given
+    Profile(Option<String>),
+    /// Use the named model for the current conversation, or pick one from a list
+    Model(Option<String>),
+    /// Show the usage and cost of all saved conversations
+    Usage,
+    /// Open a new conversation in a tab
+    NewTab,
+    /// Close the current tab
+    CloseTab,
+    /// Switch to the tab with the given number, starting at 1
+    Tab(usize),
+    /// Send the next messages to each of the given profiles (`<profile>` or
+    /// `<profile>:<model>`) to compare their responses, or stop comparing if none were given
+    Compare(Vec<String>),
+    /// Show the branches of the conversation, to switch between them or start a new one
+    Branches,
+    /// Send the last message again, keeping the previous response on its own branch
+    Regenerate,
+    /// Search the conversation, optionally for the given text
+    Find(Option<String>),
+    /// Search every saved conversation, optionally for the given words
+    Search(Option<String>),
+    /// Set the title of the conversation, or edit it if no title was given
+    Title(Option<String>),
+    /// Attach a file to the next message, or remove the attached files if no path was given
+    Attach(Option<String>),
+    /// Send an image (a web address or a local file) with the next message, or remove the
+    /// added images if none was given
+    Image(Option<String>),
+    /// Run a shell command, after confirming it, and attach its output to the next message
+    Shell(String),
+    /// Pick a shell or Python code block from the responses to run
+    Run,
+    /// Pick a diff from the responses to apply to the files in the current directory
+    Apply,
+}
+
+/// Parses a slash command typed into the input box, or a shell command starting with `!`.
+/// Returns `None` if the input is a regular chat message.
+pub fn parse_command(input: &str) -> Option<Result<Command, String>> {
+    let input = input.trim();
+
+    if let Some(command) = input.strip_prefix('!') {
+        return Some(match command.trim() {
+            "" => Err("Usage: !<command>".to_string()),
+            command => Ok(Command::Shell(command.to_owned())),
+        });
+    }
+
+    if !input.starts_with('/') {
+        return None;
+    }
+
+    let mut parts = input[1..].splitn(2, char::is_whitespace);
+    let name = parts.next().unwrap_or_default();
+    let argument = parts
+        .next()
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty());
+
+    Some(match name {
+        "profile" => Ok(Command::Profile(argument)),
+        "model" => Ok(Command::Model(argument)),
+        "usage" => Ok(Command::Usage),
+        "new" => Ok(Command::NewTab),
+        "close" => Ok(Command::CloseTab),
+        "tab" => match argument.and_then(|a| a.parse().ok()) {
+            Some(number) => Ok(Command::Tab(number)),
+            None => Err("Usage: /tab <number>".to_string()),
+        },
+        "compare" => Ok(Command::Compare(
+            argument
+                .map(|a| a.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+        )),
+        "branches" => Ok(Command::Branches),
+        "regenerate" => Ok(Command::Regenerate),
+        "find" => Ok(Command::Find(argument)),
+        "search" => Ok(Command::Search(argument)),
+        "title" => Ok(Command::Title(argument)),
+        "attach" => Ok(Command::Attach(argument)),
+        "image" => Ok(Command::Image(argument)),
+        "run" => Ok(Command::Run),
+        "apply" => Ok(Command::Apply),
+        "sh" => match argument {
+            Some(command) => Ok(Command::Shell(command)),
+            None => Err("Usage: /sh <command>".to_string()),
+        },
+        _ => Err(format!("Unknown command: /{name}")),
+    })
+}
@@ -0,0 +1,459 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use cursive::theme::{BaseColor, Color};
+use cursive::utils::markup::StyledString;
+use cursive::view::{Nameable, Resizable, ScrollStrategy};
+use cursive::views::{Button, Dialog, LinearLayout, Panel, ScrollView, TextView};
+use cursive::Cursive;
+use futures::executor::block_on;
+use futures::future::join_all;
+
+use crate::api::stream_response;
+use crate::config::{Config, Profile};
+use crate::context::{prepare_context, Summary};
+use crate::format::{Formatter, StreamingMessage};
+use crate::message::{Message, MessageMetadata};
+use crate::session::Conversation;
+use crate::status::Status;
+use crate::tokens::count_tokens;
+use crate::{
+    add_system_prompt, pop_messages, show_error, title_conversation, update_status, update_title,
+    AppState, ProcessedMessage, Request, SystemMessage,
+};
+
+/// The responses of several profiles or models to the same message, shown side by side
+pub struct Comparison {
+    pub panes: Vec<Pane>,
+}
+
+impl Comparison {
+    pub fn new(profiles: &[Profile]) -> Comparison {
+        Comparison {
+            panes: profiles.iter().cloned().map(Pane::new).collect(),
+        }
+    }
+
+    /// Whether the stats of any pane change over time, and need to be refreshed
+    pub fn is_animated(&self) -> bool {
+        self.panes.iter().any(|pane| pane.status.is_animated())
+    }
+}
+
+/// A column of a comparison, showing the response of one profile
+pub struct Pane {
+    pub profile: Profile,
+    pub response: Option<Message>,
+    pub error: Option<String>,
+    pub streaming: StreamingMessage,
+    pub status: Status,
+    /// Whether the response has finished streaming, or failed
+    pub done: bool,
+}
+
+impl Pane {
+    pub fn new(profile: Profile) -> Pane {
+        Pane {
+            profile,
+            response: None,
+            error: None,
+            streaming: StreamingMessage::default(),
+            status: Status::default(),
+            done: false,
+        }
+    }
+
+    /// Formats the response, or the error if it failed
+    pub fn render(&self, formatter: &Formatter) -> StyledString {
+        match (&self.error, &self.response) {
+            (Some(error), _) => {
+                StyledString::styled(format!("Error: {error}"), Color::Dark(BaseColor::Red))
+            }
+            (None, Some(response)) => formatter.format_message(response),
+            (None, None) => StyledString::new(),
+        }
+    }
+
+    /// Renders the latency and token stats shown above the response
+    pub fn stats(&mut self) -> StyledString {
+        let details = [format!("{} tokens", self.tokens())];
+        self.status
+            .render(&self.profile.name, self.profile.model(), &details)
+    }
+
+    /// The tokens in the response, as reported by the provider or counted while streaming
+    pub fn tokens(&self) -> u64 {
+        match &self.response {
+            Some(Message {
+                metadata:
+                    MessageMetadata {
+                        usage: Some(usage), ..
+                    },
+                ..
+            }) => usage.completion_tokens,
+            Some(response) => count_tokens(self.profile.model(), &response.content) as u64,
+            None => 0,
+        }
+    }
+}
+
+/// What preparing the context of a pane added to its copy of the conversation
+pub struct PaneContext {
+    pub summary: Option<Summary>,
+    /// The usage of the requests that summarized the conversation
+    pub background_usage: Vec<MessageMetadata>,
+}
+
+/// Resolves the profiles to compare, written as `<profile>` or `<profile>:<model>`
+pub fn parse_targets(config: &Config, targets: &[String]) -> Result<Vec<Profile>, String> {
+    if targets.len() < 2 {
+        return Err(
+            "Choose at least two profiles to compare, e.g. /compare openai:gpt-4o anthropic"
+                .to_string(),
+        );
+    }
+
+    targets
+        .iter()
+        .map(|target| {
+            let (name, model) = match target.split_once(':') {
+                Some((name, model)) => (name, Some(model)),
+                None => (target.as_str(), None),
+            };
+
+            let mut profile = config
+                .profile(name)
+                .ok_or_else(|| format!("The profile '{name}' does not exist"))?
+                .to_owned();
+
+            if let Some(model) = model {
+                profile.set_model(model.to_owned());
+            }
+
+            Ok(profile)
+        })
+        .collect()
+}
+
+/// Sends a message to several profiles at once, streaming each response to its own pane of
+/// the comparison. The message stays in the conversation until a response is chosen.
+/// Returns what the context of each pane added, to keep along with the chosen response.
+pub fn compare_responses(
+    client: &surf::Client,
+    request_config: &Config,
+    conversation: &mut Conversation,
+    m: Message,
+    profiles: &[Profile],
+    processed_msg_send: &Sender<ProcessedMessage>,
+) -> Vec<PaneContext> {
+    let profile_name = m.metadata.profile.to_owned().unwrap_or_default();
+    add_system_prompt(
+        conversation,
+        request_config.profile(&profile_name),
+        processed_msg_send,
+    );
+
+    conversation.messages.push(m.to_owned());
+
+    processed_msg_send
+        .send(ProcessedMessage::ChatMessage(Ok(Box::new(m))))
+        .unwrap();
+
+    let responses = profiles.iter().enumerate().map(|(pane, profile)| {
+        // Each profile trims or summarizes the conversation to fit its own context window
+        let mut conversation = conversation.to_owned();
+        let usage = conversation.background_usage.len();
+
+        // Tag the updates of each response with its pane
+        let (pane_send, pane_recv) = channel::<ProcessedMessage>();
+        let forward_send = processed_msg_send.to_owned();
+
+        thread::spawn(move || {
+            for update in pane_recv {
+                forward_send
+                    .send(ProcessedMessage::Comparison(pane, Box::new(update)))
+                    .ok();
+            }
+        });
+
+        async move {
+            pane_send
+                .send(ProcessedMessage::SystemMessage(
+                    SystemMessage::ResponsePending,
+                ))
+                .unwrap();
+
+            let response =
+                match prepare_context(client, &request_config.context, profile, &mut conversation)
+                    .await
+                {
+                    Ok((_, messages)) => {
+                        stream_response(client, profile, &messages, &[], &pane_send).await
+                    }
+                    Err(error) => Err(error),
+                };
+
+            // The stream already sent the finished response
+            if let Err(error) = response {
+                pane_send
+                    .send(ProcessedMessage::ChatMessage(Err(error)))
+                    .unwrap();
+            }
+
+            pane_send
+                .send(ProcessedMessage::SystemMessage(SystemMessage::ResponseDone))
+                .unwrap();
+
+            PaneContext {
+                summary: conversation.summary,
+                background_usage: conversation.background_usage.split_off(usage),
+            }
+        }
+    });
+
+    block_on(join_all(responses))
+}
+
+/// Adds the response chosen from a comparison to the conversation, along with the summary and
+/// usage of its pane, or removes the compared message if none was chosen
+pub fn choose_response(
+    request_config: &Config,
+    conversation: &mut Conversation,
+    mut panes: Vec<PaneContext>,
+    choice: Option<(usize, Message)>,
+    titling: &Arc<AtomicBool>,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) {
+    match choice {
+        Some((pane, message)) => {
+            if pane < panes.len() {
+                let context = panes.swap_remove(pane);
+                conversation.summary = context.summary;
+                conversation
+                    .background_usage
+                    .extend(context.background_usage);
+            }
+
+            conversation.messages.push(message.to_owned());
+            conversation.profile = message.metadata.profile.to_owned().unwrap_or_default();
+            conversation.model = message.metadata.model.to_owned();
+
+            if let Err(error) = conversation.save() {
+                processed_msg_send
+                    .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                    .unwrap();
+            }
+
+            if let Some(profile) = message
+                .metadata
+                .profile
+                .as_deref()
+                .and_then(|name| request_config.profile(name))
+            {
+                let mut profile = profile.to_owned();
+
+                if let Some(model) = &message.metadata.model {
+                    profile.set_model(model.to_owned());
+                }
+
+                title_conversation(
+                    request_config,
+                    conversation,
+                    &profile,
+                    titling,
+                    processed_msg_send,
+                );
+            }
+
+            processed_msg_send
+                .send(ProcessedMessage::ChatMessage(Ok(Box::new(message))))
+                .unwrap();
+        }
+        // The summaries and usage of the panes are discarded along with their responses
+        None => {
+            conversation.messages.pop();
+        }
+    }
+
+    processed_msg_send
+        .send(ProcessedMessage::SystemMessage(SystemMessage::ResponseDone))
+        .unwrap();
+}
+
+/// Shows the comparison of the current tab above the conversation, or hides the comparison
+/// of the previous tab
+pub fn show_comparison(s: &mut Cursive) {
+    if let Some(position) = s.screen_mut().find_layer_from_name("comparison") {
+        s.screen_mut().remove_layer(position);
+    }
+
+    let panes = s
+        .with_user_data(|state: &mut AppState| {
+            let formatter = &state.formatter;
+            let comparison = state.tabs[state.current].comparison.as_mut()?;
+
+            Some(
+                comparison
+                    .panes
+                    .iter_mut()
+                    .map(|pane| (pane.stats(), pane.render(formatter)))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten();
+
+    let Some(panes) = panes else {
+        return;
+    };
+
+    let mut columns = LinearLayout::horizontal();
+
+    for (i, (stats, response)) in panes.into_iter().enumerate() {
+        columns.add_child(
+            Panel::new(
+                LinearLayout::vertical()
+                    .child(TextView::new(stats).with_name(format!("comparison_stats_{i}")))
+                    .child(
+                        ScrollView::new(
+                            TextView::new(response).with_name(format!("comparison_text_{i}")),
+                        )
+                        .scroll_strategy(ScrollStrategy::StickToBottom)
+                        .full_height(),
+                    )
+                    .child(Button::new("Continue with this", move |s| {
+                        continue_comparison(s, Some(i))
+                    })),
+            )
+            .full_width(),
+        );
+    }
+
+    s.add_fullscreen_layer(
+        Dialog::around(columns)
+            .title("Comparison")
+            .button("Discard", |s| continue_comparison(s, None))
+            .with_name("comparison")
+            .full_screen(),
+    );
+
+    update_status(s);
+}
+
+/// Applies an update from the thread of a tab to a pane of its comparison
+pub fn update_comparison(s: &mut Cursive, tab: usize, pane: usize, update: ProcessedMessage) {
+    let formatted = s
+        .with_user_data(|state: &mut AppState| {
+            let formatter = &state.formatter;
+            let current = state.tabs.get(state.current)?.id == tab;
+            let tab = state.tabs.iter_mut().find(|t| t.id == tab)?;
+
+            // The comparison may have been discarded while it was streaming
+            let pane = tab.comparison.as_mut()?.panes.get_mut(pane)?;
+
+            match update {
+                ProcessedMessage::SystemMessage(SystemMessage::ResponsePending) => {
+                    pane.status.start_response();
+                    None
+                }
+                ProcessedMessage::SystemMessage(SystemMessage::ResponseDone) => {
+                    pane.status.finish_response();
+                    pane.done = true;
+                    None
+                }
+                ProcessedMessage::ChatMessage(Ok(response)) => {
+                    // The first update of the stream, or the complete response
+                    if pane.response.is_none() {
+                        let tokens = count_tokens(pane.profile.model(), &response.content);
+                        pane.status.add_response_tokens(tokens);
+                    }
+
+                    pane.response = Some(*response);
+                    current.then(|| pane.render(formatter))
+                }
+                ProcessedMessage::ChatMessage(Err(error)) => {
+                    pane.error = Some(error);
+                    current.then(|| pane.render(formatter))
+                }
+                ProcessedMessage::ResponseDelta(delta) => {
+                    let response = pane.response.as_mut()?;
+                    response.content.push_str(&delta);
+
+                    let tokens = count_tokens(pane.profile.model(), &delta);
+                    pane.status.add_response_tokens(tokens);
+
+                    current.then(|| pane.streaming.format(formatter, response))
+                }
+                _ => None,
+            }
+        })
+        .flatten();
+
+    if let Some(formatted) = formatted {
+        s.call_on_name(&format!("comparison_text_{pane}"), |view: &mut TextView| {
+            view.set_content(formatted);
+        });
+    }
+
+    update_status(s);
+}
+
+/// Continues the conversation of the current tab with the response in a pane of its
+/// comparison, or discards the comparison along with the message if no pane was chosen
+fn continue_comparison(s: &mut Cursive, pane: Option<usize>) {
+    let result = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab_mut();
+            let comparison = tab.comparison.as_ref()?;
+
+            let chosen = match pane.map(|i| (i, &comparison.panes[i])) {
+                Some((_, pane)) if !pane.done => {
+                    return Some(Err("The response hasn't finished yet".to_string()))
+                }
+                Some((
+                    i,
+                    Pane {
+                        response: Some(response),
+                        error: None,
+                        profile,
+                        ..
+                    },
+                )) => Some((i, response.to_owned(), profile.to_owned())),
+                Some(_) => return Some(Err("There is no response to continue with".to_string())),
+                None => None,
+            };
+
+            tab.comparison = None;
+
+            let response = chosen.map(|(i, response, profile)| {
+                tab.status
+                    .notify(format!("Continuing with {}", profile.name));
+                tab.active_profile = profile.name.to_owned();
+                tab.model = Some(profile.model().to_owned());
+                tab.compare.clear();
+                (i, response)
+            });
+
+            let discarded = response.is_none();
+            tab.request_send.send(Request::Choose(response)).unwrap();
+            Some(Ok((tab.id, discarded)))
+        })
+        .flatten();
+
+    match result {
+        Some(Ok((tab, discarded))) => {
+            show_comparison(s);
+
+            // The compared message is removed along with the comparison
+            if discarded {
+                pop_messages(s, tab, 1);
+            }
+
+            update_title(s);
+            update_status(s);
+        }
+        Some(Err(error)) => show_error(s, error),
+        None => {}
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::{env, fs};
+
+use serde::Deserialize;
+
+use crate::context::ContextConfig;
+use crate::title::TitleConfig;
+use crate::tools::ToolConfig;
+use crate::usage::Price;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provider {
+    /// The OpenAI API, or any server compatible with its chat completions endpoint
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// An Azure OpenAI resource
+    Azure,
+    /// The Anthropic Messages API
+    Anthropic,
+    /// A local Ollama server
+    Ollama,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthScheme {
+    /// `Authorization: Bearer <key>`, used by OpenAI and most compatible servers
+    Bearer,
+    /// `api-key: <key>`
+    ApiKey,
+    /// `x-api-key: <key>`
+    XApiKey,
+    /// No authentication header at all (e.g. local servers)
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeySource {
+    /// Read the key from an environment variable
+    Env(String),
+    /// Use the key as written in the config file
+    Value(String),
+    /// Run a shell command and use its (trimmed) standard output as the key. The command is
+    /// only run again if the key is rejected.
+    Command(String),
+}
+
+/// The keys that key commands returned, by profile
+static COMMAND_KEYS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub provider: Provider,
+    base_url: Option<String>,
+    auth: Option<AuthScheme>,
+    key: Option<KeySource>,
+    model: Option<String>,
+    /// Maximum number of tokens to generate, required by Anthropic
+    pub max_tokens: Option<u32>,
+    /// Context window of the model in tokens, for models that aren't known
+    pub context_window: Option<usize>,
+    /// A system message that starts every conversation
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Azure deployment name, defaults to the model name
+    deployment: Option<String>,
+    /// Azure API version
+    #[serde(default = "default_api_version")]
+    pub api_version: String,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            provider: Provider::default(),
+            base_url: None,
+            auth: None,
+            key: None,
+            model: None,
+            max_tokens: None,
+            context_window: None,
+            system_prompt: None,
+            headers: BTreeMap::new(),
+            deployment: None,
+            api_version: default_api_version(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn base_url(&self) -> &str {
+        let default_url = match self.provider {
+            Provider::OpenAi | Provider::Azure => "https://api.openai.com/v1",
+            Provider::Anthropic => "https://api.anthropic.com/v1",
+            Provider::Ollama => "http://localhost:11434",
+        };
+
+        self.base_url
+            .as_deref()
+            .unwrap_or(default_url)
+            .trim_end_matches('/')
+    }
+
+    pub fn auth(&self) -> AuthScheme {
+        match (&self.auth, &self.provider) {
+            (Some(auth), _) => auth.to_owned(),
+            (None, Provider::OpenAi) => AuthScheme::Bearer,
+            (None, Provider::Azure) => AuthScheme::ApiKey,
+            (None, Provider::Anthropic) => AuthScheme::XApiKey,
+            (None, Provider::Ollama) => AuthScheme::None,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        let default_model = match self.provider {
+            Provider::OpenAi | Provider::Azure => "gpt-3.5-turbo",
+            Provider::Anthropic => "claude-3-5-sonnet-latest",
+            Provider::Ollama => "llama3",
+        };
+
+        self.model.as_deref().unwrap_or(default_model)
+    }
+
+    /// Overrides the model configured for this profile
+    pub fn set_model(&mut self, model: String) {
+        self.model = Some(model);
+    }
+
+    pub fn deployment(&self) -> &str {
+        self.deployment.as_deref().unwrap_or(self.model())
+    }
+
+    /// Resolves the API key for this profile. Returns `None` if the profile does not use one.
+    pub fn api_key(&self) -> Result<Option<String>, String> {
+        if let AuthScheme::None = self.auth() {
+            return Ok(None);
+        }
+
+        let default_key = match self.provider {
+            Provider::OpenAi => KeySource::Env("OPENAI_API_KEY".to_string()),
+            Provider::Azure => KeySource::Env("AZURE_OPENAI_API_KEY".to_string()),
+            Provider::Anthropic => KeySource::Env("ANTHROPIC_API_KEY".to_string()),
+            Provider::Ollama => KeySource::Env("OLLAMA_API_KEY".to_string()),
+        };
+
+        let key = match self.key.as_ref().unwrap_or(&default_key) {
+            KeySource::Env(var) => env::var(var).map_err(|_| {
+                format!(
+                    "{var} is not set. Please set this environment variable to the API key for the '{}' profile.",
+                    self.name
+                )
+            })?,
+            KeySource::Value(key) => key.to_owned(),
+            KeySource::Command(command) => {
+                if let Some(key) = COMMAND_KEYS.lock().unwrap().get(&self.name) {
+                    return Ok(Some(key.to_owned()));
+                }
+
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .map_err(|e| format!("Could not run key command for the '{}' profile: {e}", self.name))?;
+
+                if !output.status.success() {
+                    return Err(format!(
+                        "Key command for the '{}' profile failed: {}",
+                        self.name,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+
+                let key = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+                COMMAND_KEYS
+                    .lock()
+                    .unwrap()
+                    .insert(self.name.to_owned(), key.to_owned());
+                key
+            }
+        };
+
+        Ok(Some(key))
+    }
+
+    /// Forgets the key that the key command returned, so the command is run again for the next
+    /// request
+    pub fn forget_key(&self) {
+        COMMAND_KEYS.lock().unwrap().remove(&self.name);
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub titles: TitleConfig,
+    #[serde(default)]
+    pub tools: ToolConfig,
+    /// Prices per model (name prefix), in dollars per million tokens
+    #[serde(default)]
+    pub prices: BTreeMap<String, Price>,
+}
+
+pub const DEFAULT_PROFILE: &str = "openai";
+
+fn default_api_version() -> String {
+    "2023-05-15".to_string()
+}
+
+impl Config {
+    /// Loads the config file from `$CHATGPT_TUI_CONFIG`, or `<config dir>/chatgpt-tui/config.toml`.
+    /// If there is no config file, a single OpenAI profile is used.
+    pub fn load() -> Result<Config, String> {
+        let mut config = match config_path() {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+                toml::from_str::<Config>(&contents)
+                    .map_err(|e| format!("Could not parse {}: {e}", path.display()))?
+            }
+            _ => Config {
+                default_profile: None,
+                profiles: BTreeMap::new(),
+                context: ContextConfig::default(),
+                titles: TitleConfig::default(),
+                tools: ToolConfig::default(),
+                prices: BTreeMap::new(),
+            },
+        };
+
+        if config.profiles.is_empty() {
+            config
+                .profiles
+                .insert(DEFAULT_PROFILE.to_string(), Profile::default());
+        }
+
+        for (name, profile) in config.profiles.iter_mut() {
+            profile.name = name.to_owned();
+
+            if let (Provider::Azure, None) = (&profile.provider, &profile.base_url) {
+                return Err(format!(
+                    "The '{name}' profile uses Azure, but does not set base-url to the resource endpoint"
+                ));
+            }
+        }
+
+        if let Some(name) = &config.default_profile {
+            if !config.profiles.contains_key(name) {
+                return Err(format!("The default profile '{name}' does not exist"));
+            }
+        }
+
+        if let Some(name) = &config.titles.profile {
+            if !config.profiles.contains_key(name) {
+                return Err(format!("The titles profile '{name}' does not exist"));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The profile that should be active when the application starts
+    pub fn default_profile(&self) -> &Profile {
+        self.default_profile
+            .as_ref()
+            .and_then(|name| self.profiles.get(name))
+            .or_else(|| self.profiles.get(DEFAULT_PROFILE))
+            .unwrap_or_else(|| self.profiles.values().next().unwrap())
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    match env::var_os("CHATGPT_TUI_CONFIG") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir().map(|dir| dir.join("chatgpt-tui").join("config.toml")),
+    }
+}
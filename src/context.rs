@@ -0,0 +1,422 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use surf::Client;
+
+use crate::api::complete_response;
+use crate::config::Profile;
+use crate::message::{Message, MessageMetadata, Role};
+use crate::session::Conversation;
+use crate::tokens::{context_limit, count_tokens, message_tokens, TOKENS_PER_REPLY};
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContextStrategy {
+    /// Always send the whole conversation
+    None,
+    /// Drop the oldest messages, keeping system messages
+    #[default]
+    DropOldest,
+    /// Ask the model to summarize the oldest messages, and send the summary instead
+    Summarize,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContextConfig {
+    #[serde(default)]
+    pub strategy: ContextStrategy,
+    /// Tokens that are kept free for the response
+    #[serde(default = "default_reserve")]
+    pub reserve: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            strategy: ContextStrategy::default(),
+            reserve: default_reserve(),
+        }
+    }
+}
+
+fn default_reserve() -> usize {
+    1024
+}
+
+/// A summary of the messages before `until`, which is sent in their place
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Summary {
+    pub content: String,
+    pub until: usize,
+}
+
+impl Summary {
+    fn message(&self) -> Message {
+        Message {
+            role: Role::System,
+            content: format!("Summary of the earlier conversation:\n{}", self.content),
+            ..Default::default()
+        }
+    }
+}
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and an AI assistant. Keep every fact, decision, name and piece of code that may be needed to continue the conversation. Reply with the summary only.";
+
+/// Builds the messages that are sent when the conversation starts at `start`.
+/// System messages before `start` are always kept, the summary takes the place of the
+/// messages it covers, and messages that are only shown are left out.
+pub fn request_messages(
+    messages: &[Message],
+    start: usize,
+    summary: Option<&Summary>,
+) -> Vec<Message> {
+    let mut request: Vec<Message> = messages[..start]
+        .iter()
+        .filter(|m| matches!(m.role, Role::System) && !m.metadata.local)
+        .cloned()
+        .collect();
+
+    if let Some(summary) = summary.filter(|s| s.until <= start && start > first_message(messages)) {
+        request.push(summary.message());
+    }
+
+    request.extend(
+        messages[start..]
+            .iter()
+            .filter(|m| !m.metadata.local)
+            .cloned(),
+    );
+    request
+}
+
+/// The index of the first message that isn't a system message
+fn first_message(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .position(|m| !matches!(m.role, Role::System))
+        .unwrap_or(messages.len())
+}
+
+/// Finds the oldest user message that the conversation can start at while fitting in `budget`
+/// tokens. If even the last user message doesn't fit, the conversation starts there anyway.
+/// With a summary, it doesn't start between the first message and the end of the summary, as
+/// the messages before the start would then be lost.
+fn fit(model: &str, messages: &[Message], summary: Option<&Summary>, budget: usize) -> usize {
+    let first = first_message(messages);
+    let tokens: Vec<usize> = messages.iter().map(|m| message_tokens(model, m)).collect();
+    let summary_tokens = summary
+        .map(|s| message_tokens(model, &s.message()))
+        .unwrap_or(0);
+    let until = summary.map(|s| s.until).unwrap_or(first);
+
+    let starts: Vec<usize> = (first..messages.len())
+        .filter(|&i| matches!(messages[i].role, Role::User))
+        .filter(|&i| i == first || i >= until)
+        .collect();
+
+    for &start in &starts {
+        let pinned: usize = (0..start)
+            .filter(|&i| matches!(messages[i].role, Role::System))
+            .map(|i| tokens[i])
+            .sum();
+        let summary = match summary {
+            Some(summary) if summary.until <= start && start > first => summary_tokens,
+            _ => 0,
+        };
+        let total = pinned + summary + tokens[start..].iter().sum::<usize>() + TOKENS_PER_REPLY;
+
+        if total <= budget {
+            return start;
+        }
+    }
+
+    starts.last().copied().unwrap_or(first)
+}
+
+/// The messages that have fallen out of the context since the last summary, and have to be
+/// summarized before the conversation fits in `budget` tokens. They are capped so that the
+/// request to summarize them fits in `budget` too, which leaves the rest for another summary.
+fn summary_range(
+    model: &str,
+    messages: &[Message],
+    summary: Option<&Summary>,
+    budget: usize,
+) -> Option<Range<usize>> {
+    let first = first_message(messages);
+    let until = summary.map(|s| s.until).unwrap_or(first).max(first);
+    let start = fit(model, messages, summary, budget);
+
+    if start <= until {
+        return None;
+    }
+
+    let mut tokens = count_tokens(model, SUMMARY_PROMPT)
+        + summary
+            .map(|s| count_tokens(model, &s.content))
+            .unwrap_or(0)
+        + TOKENS_PER_REPLY;
+    let mut end = until;
+
+    // Always take at least one message, so every summary moves on
+    while end < start && (end == until || tokens + message_tokens(model, &messages[end]) <= budget)
+    {
+        tokens += message_tokens(model, &messages[end]);
+        end += 1;
+    }
+
+    Some(until..end)
+}
+
+/// Decides which messages of the conversation are sent to the model, summarizing the older ones
+/// if the strategy asks for it. Returns the index of the first message that is still in context,
+/// along with the messages to send.
+pub async fn prepare_context(
+    client: &Client,
+    config: &ContextConfig,
+    profile: &Profile,
+    conversation: &mut Conversation,
+) -> Result<(usize, Vec<Message>), String> {
+    let messages = &conversation.messages.to_vec();
+    let limit = profile
+        .context_window
+        .or_else(|| context_limit(profile.model()));
+
+    let budget = match (&config.strategy, limit) {
+        (ContextStrategy::None, _) | (_, None) => {
+            return Ok((0, request_messages(messages, 0, None)))
+        }
+        (_, Some(limit)) => limit.saturating_sub(config.reserve),
+    };
+
+    if let ContextStrategy::Summarize = config.strategy {
+        let first = first_message(messages);
+
+        // Adding a summary can push the start further on, so the messages in between are
+        // summarized too, until the summary ends where the conversation starts
+        while let Some(range) = summary_range(
+            profile.model(),
+            messages,
+            conversation.summary.as_ref(),
+            budget,
+        ) {
+            let (content, response) = summarize(
+                client,
+                profile,
+                conversation
+                    .summary
+                    .as_ref()
+                    .filter(|_| range.start > first),
+                &messages[range.clone()],
+            )
+            .await?;
+
+            conversation.background_usage.push(response);
+            conversation.summary = Some(Summary {
+                content,
+                until: range.end,
+            });
+        }
+    }
+
+    let summary = match config.strategy {
+        ContextStrategy::Summarize => conversation.summary.as_ref(),
+        _ => None,
+    };
+    let start = fit(profile.model(), messages, summary, budget);
+
+    Ok((start, request_messages(messages, start, summary)))
+}
+
+/// Asks the model to summarize the messages. Returns the summary along with the metadata of the
+/// response, which has its usage.
+async fn summarize(
+    client: &Client,
+    profile: &Profile,
+    previous: Option<&Summary>,
+    messages: &[Message],
+) -> Result<(String, MessageMetadata), String> {
+    let mut transcript = String::new();
+
+    if let Some(previous) = previous {
+        transcript.push_str(&format!(
+            "Summary of the conversation before this:\n{}\n\n",
+            previous.content
+        ));
+    }
+
+    for m in messages.iter().filter(|m| !m.metadata.local) {
+        let speaker = match m.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+            Role::System => "System",
+            Role::Tool => "Tool",
+        };
+
+        transcript.push_str(&format!("{speaker}: {}\n\n", m.sent_content()));
+    }
+
+    let request = [
+        Message {
+            role: Role::System,
+            content: SUMMARY_PROMPT.to_string(),
+            ..Default::default()
+        },
+        Message {
+            role: Role::User,
+            content: transcript,
+            ..Default::default()
+        },
+    ];
+
+    complete_response(client, profile, &request)
+        .await
+        .map(|m| (m.content.trim().to_owned(), m.metadata))
+        .map_err(|e| format!("Could not summarize the conversation: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "gpt-4o";
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message(Role::System, "You are a helpful assistant."),
+            message(Role::User, "What is the capital of France?"),
+            message(Role::Assistant, "The capital of France is Paris."),
+            message(Role::User, "And of Italy?"),
+            message(Role::Assistant, "Rome."),
+            message(Role::User, "And of Spain?"),
+            message(Role::Assistant, "Madrid."),
+        ]
+    }
+
+    /// The tokens of a request starting at `start`, keeping the system message
+    fn request_tokens(messages: &[Message], start: usize) -> usize {
+        message_tokens(MODEL, &messages[0])
+            + messages[start..]
+                .iter()
+                .map(|m| message_tokens(MODEL, m))
+                .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+
+    #[test]
+    fn starts_at_the_oldest_message_that_fits() {
+        let messages = conversation();
+
+        let everything = request_tokens(&messages, 1);
+        assert_eq!(fit(MODEL, &messages, None, everything), 1);
+        assert_eq!(fit(MODEL, &messages, None, everything - 1), 3);
+        assert_eq!(fit(MODEL, &messages, None, request_tokens(&messages, 3)), 3);
+    }
+
+    #[test]
+    fn starts_at_the_last_user_message_if_nothing_fits() {
+        let messages = conversation();
+
+        assert_eq!(fit(MODEL, &messages, None, 0), 5);
+        assert_eq!(fit(MODEL, &messages[..1], None, 0), 1);
+    }
+
+    #[test]
+    fn counts_the_summary() {
+        let messages = conversation();
+        let summary = Summary {
+            content: "The user asked about the capital of France.".to_string(),
+            until: 3,
+        };
+
+        let budget = request_tokens(&messages, 3);
+        assert_eq!(fit(MODEL, &messages, None, budget), 3);
+        assert_eq!(fit(MODEL, &messages, Some(&summary), budget), 5);
+
+        let with_summary = budget + message_tokens(MODEL, &summary.message());
+        assert_eq!(fit(MODEL, &messages, Some(&summary), with_summary), 3);
+    }
+
+    #[test]
+    fn does_not_start_inside_the_summary() {
+        let messages = conversation();
+        let summary = Summary {
+            content: "The user asked about the capitals of France and Italy.".to_string(),
+            until: 5,
+        };
+
+        let budget = request_tokens(&messages, 3);
+        assert_eq!(fit(MODEL, &messages, None, budget), 3);
+        assert_eq!(fit(MODEL, &messages, Some(&summary), budget), 5);
+        assert_eq!(fit(MODEL, &messages, Some(&summary), usize::MAX), 1);
+    }
+
+    #[test]
+    fn summarizes_what_the_summary_pushes_out() {
+        // The system message leaves room to summarize, as it isn't part of the summary request
+        let mut messages = conversation();
+        messages[0].content = "You are a helpful assistant. ".repeat(20);
+        let budget = request_tokens(&messages, 3);
+
+        assert_eq!(summary_range(MODEL, &messages, None, budget), Some(1..3));
+
+        // The summary doesn't fit along with the messages from 3, so they start at 5
+        let summary = Summary {
+            content: "The user asked about the capital of France, which is Paris.".to_string(),
+            until: 3,
+        };
+        assert_eq!(fit(MODEL, &messages, Some(&summary), budget), 5);
+        assert_eq!(
+            summary_range(MODEL, &messages, Some(&summary), budget),
+            Some(3..5)
+        );
+
+        let summary = Summary {
+            until: 5,
+            ..summary
+        };
+        assert_eq!(
+            summary_range(MODEL, &messages, Some(&summary), budget),
+            None
+        );
+    }
+
+    #[test]
+    fn summarizes_what_fits_in_the_context() {
+        let messages = conversation();
+
+        assert_eq!(summary_range(MODEL, &messages, None, 0), Some(1..2));
+        assert_eq!(summary_range(MODEL, &messages, None, usize::MAX), None);
+    }
+
+    #[test]
+    fn keeps_system_messages() {
+        let messages = conversation();
+        let request = request_messages(&messages, 3, None);
+
+        assert_eq!(request.len(), 5);
+        assert!(matches!(request[0].role, Role::System));
+        assert_eq!(request[1].content, "And of Italy?");
+    }
+
+    #[test]
+    fn leaves_out_shown_messages() {
+        let mut messages = conversation();
+        let mut output = message(Role::System, "From the shell code block:\n```\nhi\n```");
+        output.metadata.local = true;
+        messages.insert(3, output);
+
+        let request = request_messages(&messages, 0, None);
+        assert_eq!(request.len(), 7);
+        assert!(request.iter().all(|m| !m.metadata.local));
+        assert_eq!(message_tokens(MODEL, &messages[3]), 0);
+    }
+}
@@ -12,20 +12,389 @@ use cursive::theme::{BaseColor, Color, ColorStyle, ColorType, Effect, Style};
 use cursive::utils::markup::{StyledIndexedSpan, StyledString};
 use cursive::utils::span::IndexedCow;
 use cursive_syntect::translate_effects;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::str;
 use syntect::easy::HighlightLines;
-use syntect::highlighting::Style as HighlightingStyle;
+use syntect::highlighting::{Style as HighlightingStyle, Theme};
 use syntect::parsing::SyntaxSet;
 use syntect::Error;
 
-use crate::{Message, Role};
+use crate::message::{ContentPart, Message, Role};
 
-pub fn format_message(
-    syntax_set: &SyntaxSet,
-    theme: &syntect::highlighting::Theme,
-    m: &Message,
-) -> StyledString {
-    let mut formatted_user = match m.role {
+/// Highlighted code blocks that are kept before the cache is cleared
+pub const CODE_CACHE_SIZE: usize = 256;
+
+/// The lines of a tool result that are shown, the model sees all of them
+const TOOL_RESULT_LINES: usize = 10;
+
+/// The info string and contents of a code block
+type CodeBlockKey = (Vec<u8>, Vec<u8>);
+
+/// Formats messages for display, caching highlighted code blocks so they aren't highlighted
+/// again every time a message is rendered
+pub struct Formatter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+    code_cache: RefCell<HashMap<CodeBlockKey, StyledString>>,
+    code_cache_size: usize,
+}
+
+impl Formatter {
+    /// Creates a formatter that caches up to `code_cache_size` highlighted code blocks
+    pub fn new(syntax_set: SyntaxSet, theme: Theme, code_cache_size: usize) -> Formatter {
+        Formatter {
+            syntax_set,
+            theme,
+            code_cache: RefCell::new(HashMap::new()),
+            code_cache_size,
+        }
+    }
+
+    pub fn format_message(&self, m: &Message) -> StyledString {
+        let mut formatted = format_header(m);
+
+        formatted.append(match m.role {
+            Role::User => format_user_text(m),
+            Role::Assistant => self.format_text(m.content.trim(), true),
+            // Messages that are only shown are formatted, since they hold code blocks
+            Role::System if m.metadata.local => self.format_text(m.content.trim(), false),
+            Role::System => StyledString::from(m.content.trim()),
+            Role::Tool => format_tool_result(m),
+        });
+
+        for part in &m.parts {
+            formatted.append(self.format_part(m, part));
+        }
+
+        format_tool_calls(&mut formatted, m);
+
+        format_footer(&mut formatted, m);
+        formatted
+    }
+
+    /// Formats a part of the content that follows the text of a message. Text is formatted
+    /// like the text of the message, and images are shown as chips.
+    fn format_part(&self, m: &Message, part: &ContentPart) -> StyledString {
+        let mut formatted = StyledString::plain("\n");
+
+        match part {
+            ContentPart::Text { text } => formatted.append(match m.role {
+                Role::Assistant => self.format_text(text.trim(), true),
+                _ => StyledString::from(text.trim()),
+            }),
+            ContentPart::ImageUrl { .. } => formatted.append_styled(
+                format!(" image: {} ", part.label()),
+                Style {
+                    effects: enum_set!(Effect::Reverse),
+                    color: ColorStyle::new(BaseColor::Magenta, ColorType::InheritParent),
+                },
+            ),
+        }
+
+        formatted
+    }
+
+    pub fn syntax_set(&self) -> &SyntaxSet {
+        &self.syntax_set
+    }
+
+    /// Formats markdown text. Code blocks are only cached if `cache_code` is set, since
+    /// code blocks in an unfinished response may not be complete.
+    fn format_text(&self, text: &str, cache_code: bool) -> StyledString {
+        let arena = Arena::new();
+        let mut options = ComrakOptions::default();
+        options.extension.strikethrough = true;
+        options.extension.tasklist = true;
+
+        format_markdown(self, parse_document(&arena, text, &options), cache_code)
+    }
+
+    /// Highlights a code block, reusing the result if the same block was highlighted before
+    fn highlight_code(&self, info: &[u8], literal: &[u8], cache_code: bool) -> StyledString {
+        let key = (info.to_vec(), literal.to_vec());
+
+        if let Some(cached) = self.code_cache.borrow().get(&key) {
+            return cached.to_owned();
+        }
+
+        let syntax_set = &self.syntax_set;
+
+        // We assume that the first tag in the info string is the language
+        let mut first_space_idx = 0;
+        while first_space_idx < info.len()
+            && !char::is_ascii_whitespace(&(info[first_space_idx] as char))
+        {
+            first_space_idx += 1;
+        }
+
+        let token = str::from_utf8(&info[..first_space_idx]).unwrap();
+
+        // Diffs are colored by whether lines are added or removed, rather than by their syntax
+        if is_diff(token) {
+            return format_diff(str::from_utf8(literal).unwrap());
+        }
+
+        let language = syntax_set.find_syntax_by_token(token).unwrap_or_else(|| {
+            syntax_set
+                .find_syntax_by_first_line(str::from_utf8(literal).unwrap().trim_start())
+                .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+        });
+
+        let mut highlighter = HighlightLines::new(language, &self.theme);
+        let parsed_code = match parse_code(
+            str::from_utf8(literal).unwrap(),
+            &mut highlighter,
+            syntax_set,
+        ) {
+            Ok(code) => code,
+            Err(_) => StyledString::from(str::from_utf8(literal).unwrap()),
+        };
+
+        if cache_code && self.code_cache_size > 0 {
+            let mut code_cache = self.code_cache.borrow_mut();
+
+            if code_cache.len() >= self.code_cache_size {
+                code_cache.clear();
+            }
+
+            code_cache.insert(key, parsed_code.to_owned());
+        }
+
+        parsed_code
+    }
+}
+
+/// Whether the language of a code block is a diff
+pub fn is_diff(language: &str) -> bool {
+    matches!(language, "diff" | "patch")
+}
+
+/// Colors the lines of a diff by whether they are added, removed or mark a hunk
+fn format_diff(diff: &str) -> StyledString {
+    let mut formatted = StyledString::new();
+    let mut lines = diff.split_inclusive('\n').peekable();
+
+    // Removed lines can start with `--` too, so `---` only starts a file header if `+++`
+    // follows it
+    let mut after_old_path = false;
+
+    while let Some(line) = lines.next() {
+        let header = after_old_path
+            || (line.starts_with("--- ")
+                && lines.peek().is_some_and(|next| next.starts_with("+++ ")));
+        after_old_path = header && !after_old_path;
+
+        let style = if header {
+            Style::from(Effect::Bold)
+        } else if line.starts_with('+') {
+            Style::from(ColorStyle::new(BaseColor::Green, ColorType::InheritParent))
+        } else if line.starts_with('-') {
+            Style::from(ColorStyle::new(BaseColor::Red, ColorType::InheritParent))
+        } else if line.starts_with("@@") {
+            Style::from(ColorStyle::new(BaseColor::Cyan, ColorType::InheritParent))
+        } else {
+            Style::default()
+        };
+
+        formatted.append_styled(line, style);
+    }
+
+    formatted
+}
+
+/// A fenced code block in a message
+pub struct CodeBlockText {
+    /// The info string after the opening fence
+    pub info: String,
+    pub code: String,
+}
+
+/// Finds the code blocks in markdown text, in order
+pub fn code_blocks(text: &str) -> Vec<CodeBlockText> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, text, &ComrakOptions::default());
+
+    root.descendants()
+        .filter_map(|node| match node.data.borrow().value {
+            CodeBlock(ref code_node) => Some(CodeBlockText {
+                info: String::from_utf8_lossy(&code_node.info).to_string(),
+                code: String::from_utf8_lossy(&code_node.literal).to_string(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Renders a response while it is streamed. Blocks that are complete are only formatted
+/// once, so each update only formats the block that is still being written.
+#[derive(Default)]
+pub struct StreamingMessage {
+    /// The formatted blocks that are complete
+    complete: StyledString,
+    /// The length of the content that `complete` was formatted from
+    complete_len: usize,
+}
+
+impl StreamingMessage {
+    /// Formats `m`, whose content has grown since the last call
+    pub fn format(&mut self, formatter: &Formatter, m: &Message) -> StyledString {
+        let content = &m.content;
+
+        // Leading whitespace is trimmed, like in `format_message`
+        if self.complete_len == 0 {
+            self.complete_len = content.len() - content.trim_start().len();
+        }
+
+        if let Some(boundary) = last_block_boundary(&content[self.complete_len..]) {
+            let end = self.complete_len + boundary;
+            let block = formatter.format_text(content[self.complete_len..end].trim(), true);
+
+            if !self.complete.is_empty() {
+                separate_blocks(&mut self.complete);
+            }
+
+            self.complete.append(block);
+            self.complete_len = end;
+        }
+
+        let mut formatted = format_header(m);
+        formatted.append(self.complete.to_owned());
+
+        let rest = content[self.complete_len..].trim();
+
+        if !rest.is_empty() {
+            if !self.complete.is_empty() {
+                separate_blocks(&mut formatted);
+            }
+
+            formatted.append(formatter.format_text(rest, false));
+        }
+
+        format_footer(&mut formatted, m);
+        formatted
+    }
+}
+
+/// Finds the start of the last line in `text` that begins a new top-level block, i.e. an
+/// unindented line after a blank line that isn't inside a code fence. Everything before it
+/// is made of complete blocks, which won't change as more text is streamed.
+fn last_block_boundary(text: &str) -> Option<usize> {
+    let mut boundary = None;
+    let mut fence: Option<String> = None;
+    let mut after_blank = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+
+        match &fence {
+            Some(marker) => {
+                if trimmed.starts_with(marker.as_str())
+                    && trimmed.trim_end().chars().all(|c| marker.starts_with(c))
+                {
+                    fence = None;
+                }
+            }
+            None => {
+                if after_blank && !line.starts_with(char::is_whitespace) {
+                    boundary = Some(offset);
+                }
+
+                let marker: String = trimmed
+                    .chars()
+                    .take_while(|&c| c == '`' || c == '~')
+                    .collect();
+
+                if marker.len() >= 3 && marker.chars().all(|c| marker.starts_with(c)) {
+                    fence = Some(marker);
+                }
+            }
+        }
+
+        after_blank = fence.is_none() && trimmed.is_empty();
+        offset += line.len();
+    }
+
+    boundary
+}
+
+/// Ends formatted blocks with a blank line, so another block can be appended. Paragraphs at
+/// the end of a document don't get one when they're formatted.
+fn separate_blocks(formatted: &mut StyledString) {
+    let newlines = formatted
+        .source()
+        .chars()
+        .rev()
+        .take_while(|&c| c == '\n')
+        .count();
+
+    for _ in newlines..2 {
+        formatted.append_plain("\n");
+    }
+}
+
+/// Shows the text of a user message, with a chip for each attachment instead of its contents
+fn format_user_text(m: &Message) -> StyledString {
+    let mut formatted = StyledString::from(m.content.trim());
+
+    for (i, attachment) in m.metadata.attachments.iter().enumerate() {
+        formatted.append_plain(if i == 0 { "\n" } else { " " });
+        formatted.append_styled(
+            format!(" {} ({} lines) ", attachment.label(), attachment.lines),
+            Style {
+                effects: enum_set!(Effect::Reverse),
+                color: ColorStyle::new(BaseColor::Cyan, ColorType::InheritParent),
+            },
+        );
+    }
+
+    formatted
+}
+
+/// Shows the start of the result of a tool, since results are often long files or listings
+fn format_tool_result(m: &Message) -> StyledString {
+    let content = m.content.trim_end();
+    let lines = content.lines().count();
+
+    let mut formatted = StyledString::from(
+        content
+            .lines()
+            .take(TOOL_RESULT_LINES)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+
+    if lines > TOOL_RESULT_LINES {
+        formatted.append_styled(
+            format!("\n[{} more lines]", lines - TOOL_RESULT_LINES),
+            Effect::Italic,
+        );
+    }
+
+    formatted
+}
+
+/// Lists the tools that a response called
+fn format_tool_calls(formatted: &mut StyledString, m: &Message) {
+    for (i, call) in m.tool_calls.iter().enumerate() {
+        formatted.append_plain(if i == 0 && m.content.trim().is_empty() {
+            ""
+        } else {
+            "\n"
+        });
+        formatted.append_styled(
+            format!(" {} {} ", call.function.name, call.function.arguments),
+            Style {
+                effects: enum_set!(Effect::Reverse),
+                color: ColorStyle::new(BaseColor::Yellow, ColorType::InheritParent),
+            },
+        );
+    }
+}
+
+fn format_header(m: &Message) -> StyledString {
+    let mut header = match m.role {
         Role::User => StyledString::styled(
             "You",
             Style {
@@ -34,12 +403,20 @@ pub fn format_message(
             },
         ),
         Role::Assistant => StyledString::styled(
-            "ChatGPT",
+            // Label responses with the profile they came from, since it may not be ChatGPT
+            m.metadata.profile.as_deref().unwrap_or("ChatGPT"),
             Style {
                 effects: enum_set!(Effect::Bold | Effect::Underline),
                 color: ColorStyle::new(BaseColor::Magenta, ColorType::InheritParent),
             },
         ),
+        Role::System if m.metadata.local => StyledString::styled(
+            "Output",
+            Style {
+                effects: enum_set!(Effect::Bold | Effect::Underline),
+                color: ColorStyle::new(BaseColor::Blue, ColorType::InheritParent),
+            },
+        ),
         Role::System => StyledString::styled(
             "System",
             Style {
@@ -47,34 +424,67 @@ pub fn format_message(
                 color: ColorStyle::new(BaseColor::Green, ColorType::InheritParent),
             },
         ),
+        Role::Tool => StyledString::styled(
+            "Tool",
+            Style {
+                effects: enum_set!(Effect::Bold | Effect::Underline),
+                color: ColorStyle::new(BaseColor::Yellow, ColorType::InheritParent),
+            },
+        ),
     };
 
-    let arena = Arena::new();
-    let mut options = ComrakOptions::default();
-    options.extension.strikethrough = true;
-    options.extension.tasklist = true;
+    // Named participants are told apart by their name
+    if let Some(name) = &m.name {
+        header.append_plain(format!(" ({name})"));
+    }
 
-    let formatted_contents = match m.role {
-        Role::User => StyledString::from(m.content.trim()),
-        Role::Assistant => format_markdown(
-            syntax_set,
-            theme,
-            parse_document(&arena, m.content.trim(), &options),
-        ),
-        Role::System => StyledString::from(m.content.trim()),
-    };
+    header.append_plain(": ");
+    header
+}
+
+fn format_footer(formatted: &mut StyledString, m: &Message) {
+    if let Some(categories) = &m.metadata.content_filter {
+        let reason = if categories.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", categories.join(", "))
+        };
+
+        formatted.append_plain("\n\n");
+        formatted.append_styled(
+            format!("[Response stopped by the content filter{reason}]"),
+            Style {
+                effects: enum_set!(Effect::Bold),
+                color: ColorStyle::new(BaseColor::Red, ColorType::InheritParent),
+            },
+        );
+    }
+
+    formatted.append("\n\n");
+}
+
+/// Dims a formatted message and labels it, to show that it is no longer sent to the model
+pub fn mark_out_of_context(formatted: &mut StyledString) {
+    for span in formatted.spans_attr_mut() {
+        span.attr.effects.insert(Effect::Dim);
+    }
 
-    formatted_user.append_plain(": ");
-    formatted_user.append(formatted_contents);
-    formatted_user.append("\n\n");
+    let mut marked = StyledString::styled(
+        "[not in context] ",
+        Style {
+            effects: enum_set!(Effect::Dim | Effect::Italic),
+            color: ColorStyle::inherit_parent(),
+        },
+    );
+    marked.append(formatted.to_owned());
 
-    formatted_user
+    *formatted = marked;
 }
 
 fn format_markdown<'a>(
-    syntax_set: &SyntaxSet,
-    theme: &syntect::highlighting::Theme,
+    formatter: &Formatter,
     r: &'a AstNode<'a>,
+    cache_code: bool,
 ) -> StyledString {
     let mut stack: Vec<(&AstNode, bool)> = vec![(r, true)];
     let mut string = StyledString::new();
@@ -182,41 +592,8 @@ fn format_markdown<'a>(
             }
             CodeBlock(ref code_node) => {
                 if entering {
-                    // We assume that the first tag in the info string is the language
-                    let mut first_space_idx = 0;
-                    while first_space_idx < code_node.info.len()
-                        && !char::is_ascii_whitespace(&(code_node.info[first_space_idx] as char))
-                    {
-                        first_space_idx += 1;
-                    }
-
-                    let language = syntax_set
-                        .find_syntax_by_token(
-                            str::from_utf8(&code_node.info[..first_space_idx]).unwrap(),
-                        )
-                        .unwrap_or_else(|| {
-                            syntax_set
-                                .find_syntax_by_first_line(
-                                    str::from_utf8(&code_node.literal).unwrap().trim_start(),
-                                )
-                                .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
-                        });
-
-                    let parsed_code = {
-                        let mut highlighter = HighlightLines::new(language, theme);
-                        let parsed_code = parse_code(
-                            str::from_utf8(&code_node.literal).unwrap(),
-                            &mut highlighter,
-                            syntax_set,
-                        );
-
-                        match parsed_code {
-                            Ok(code) => code,
-                            Err(_) => {
-                                StyledString::from(str::from_utf8(&code_node.literal).unwrap())
-                            }
-                        }
-                    };
+                    let parsed_code =
+                        formatter.highlight_code(&code_node.info, &code_node.literal, cache_code);
 
                     // Append another newline because the end of code blocks usually have a newline already
                     string.append(parsed_code);
@@ -409,3 +786,31 @@ fn translate_style(style: HighlightingStyle) -> Style {
         color: (foreground_color, background_color).into(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_last_block() {
+        let text = "First paragraph\n\nSecond paragraph\n\nThird";
+
+        assert_eq!(last_block_boundary(text), text.find("Third"));
+        assert_eq!(last_block_boundary("One line\nand another"), None);
+    }
+
+    #[test]
+    fn ignores_blank_lines_in_code_fences() {
+        let text = "Intro\n\n```rust\nfn a() {}\n\nfn b() {}\n";
+        assert_eq!(last_block_boundary(text), text.find("```"));
+
+        let text = "Intro\n\n~~~~\na\n\n```\nb\n~~~~\n\nAfter";
+        assert_eq!(last_block_boundary(text), text.find("After"));
+    }
+
+    #[test]
+    fn ignores_indented_lines() {
+        let text = "- Item\n\n  More of the item\n";
+        assert_eq!(last_block_boundary(text), None);
+    }
+}
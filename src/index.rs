@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::search::pattern;
+use crate::session::{load_conversations, Conversation};
+use crate::title::fallback_title;
+
+/// How much of a message is shown before and after the match in search results, in characters
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Every tab saves its conversation from its own thread, so updates to the index take turns
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// A full-text index of the saved conversations, which maps each word to the conversations
+/// that contain it
+#[derive(Serialize, Deserialize, Default)]
+struct SearchIndex {
+    words: BTreeMap<String, BTreeSet<String>>,
+    /// The words of each conversation, so they can be removed when it changes
+    conversations: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// A saved conversation that matches a search
+pub struct SessionMatch {
+    pub id: String,
+    pub label: String,
+    /// The text around the first match
+    pub snippet: String,
+    /// The word of the query that the snippet shows
+    pub term: String,
+}
+
+/// Splits text into lowercase words
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+impl SearchIndex {
+    /// Adds the words of a conversation, replacing its previous words. Returns whether they
+    /// changed, since most saves only add words that the conversation already had.
+    fn add(&mut self, conversation: &Conversation) -> bool {
+        let words: BTreeSet<String> = conversation
+            .messages
+            .all()
+            .map(|m| m.content.as_str())
+            .chain(conversation.title.as_deref())
+            .flat_map(words)
+            .collect();
+
+        if self.conversations.get(&conversation.id) == Some(&words) {
+            return false;
+        }
+
+        self.remove(&conversation.id);
+
+        for word in &words {
+            self.words
+                .entry(word.to_owned())
+                .or_default()
+                .insert(conversation.id.to_owned());
+        }
+
+        self.conversations.insert(conversation.id.to_owned(), words);
+        true
+    }
+
+    fn remove(&mut self, id: &str) {
+        for word in self.conversations.remove(id).unwrap_or_default() {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(id);
+
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// The conversations that contain every word of the query. Words of the query also match
+    /// longer words that start with them, e.g. "time" matches "timers".
+    fn find(&self, query: &str) -> BTreeSet<String> {
+        words(query)
+            .map(|term| {
+                self.words
+                    .range(term.to_owned()..)
+                    .take_while(|(word, _)| word.starts_with(&term))
+                    .flat_map(|(_, ids)| ids.iter().cloned())
+                    .collect::<BTreeSet<_>>()
+            })
+            .reduce(|a, b| a.intersection(&b).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Reads the index, building it from the saved conversations if it doesn't exist yet
+    fn load() -> Result<SearchIndex, String> {
+        let path = index_path().ok_or("Could not find the search index")?;
+
+        if let Some(index) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+        {
+            return Ok(index);
+        }
+
+        let mut index = SearchIndex::default();
+        for conversation in load_conversations()? {
+            index.add(&conversation);
+        }
+
+        index.save()?;
+        Ok(index)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = index_path().ok_or("Could not find a directory to save the search index in")?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+        }
+
+        let contents = serde_json::to_string(self).unwrap();
+
+        fs::write(&path, contents)
+            .map_err(|e| format!("Could not save the search index to {}: {e}", path.display()))
+    }
+}
+
+/// Adds a conversation that was just saved to the index, replacing its previous contents.
+/// The index is only written if the words of the conversation changed.
+pub fn update_index(conversation: &Conversation) -> Result<(), String> {
+    let _lock = INDEX_LOCK.lock().unwrap();
+
+    let mut index = SearchIndex::load()?;
+
+    match index.add(conversation) {
+        true => index.save(),
+        false => Ok(()),
+    }
+}
+
+/// Finds the saved conversations that contain every word of `query`, newest first
+pub fn search_sessions(query: &str) -> Result<Vec<SessionMatch>, String> {
+    let ids = {
+        let _lock = INDEX_LOCK.lock().unwrap();
+        SearchIndex::load()?.find(query)
+    };
+
+    let Some(term) = words(query).next() else {
+        return Ok(vec![]);
+    };
+
+    let mut conversations: Vec<Conversation> = ids
+        .iter()
+        .filter_map(|id| Conversation::load(id).ok())
+        .collect();
+
+    conversations.sort_by_key(|c| std::cmp::Reverse(c.created_at));
+
+    let pattern = pattern(&term, false, false)?;
+
+    Ok(conversations
+        .into_iter()
+        .map(|conversation| {
+            // Prefer a match in the branch that is opened
+            let snippet = conversation
+                .messages
+                .iter()
+                .chain(conversation.messages.all())
+                .find_map(|m| {
+                    let found = pattern.find(&m.content)?;
+                    Some(snippet(&m.content, found.start(), found.end()))
+                })
+                .unwrap_or_default();
+
+            let title = conversation
+                .title
+                .to_owned()
+                .or_else(|| fallback_title(&conversation.messages))
+                .unwrap_or_default();
+
+            SessionMatch {
+                label: format!("{}  {title}", conversation.created_at.format("%Y-%m-%d")),
+                id: conversation.id,
+                snippet,
+                term: term.to_owned(),
+            }
+        })
+        .collect())
+}
+
+/// The text around a match, on a single line
+fn snippet(content: &str, start: usize, end: usize) -> String {
+    let before: String = content[..start]
+        .chars()
+        .rev()
+        .take(SNIPPET_CONTEXT)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let after: String = content[end..].chars().take(SNIPPET_CONTEXT).collect();
+
+    let ellipsis = |cut: bool| if cut { "..." } else { "" };
+    let snippet = format!(
+        "{}{before}{}{after}{}",
+        ellipsis(before.len() < start),
+        &content[start..end],
+        ellipsis(end + after.len() < content.len())
+    );
+
+    snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn index_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("chatgpt-tui").join("index.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, Role};
+
+    fn conversation(id: &str, title: Option<&str>, contents: &[&str]) -> Conversation {
+        let mut conversation = Conversation::new("default");
+        conversation.id = id.to_owned();
+        conversation.title = title.map(str::to_owned);
+
+        for content in contents {
+            conversation.messages.push(Message {
+                role: Role::User,
+                content: content.to_string(),
+                ..Default::default()
+            });
+        }
+
+        conversation
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.add(&conversation("a", None, &["How do timers work in Tokio?"]));
+        index.add(&conversation(
+            "b",
+            Some("Rust lifetimes"),
+            &["What is a timeout?"],
+        ));
+        index.add(&conversation("c", None, &["Tokio, again", "with timeouts"]));
+        index
+    }
+
+    fn ids(found: BTreeSet<String>) -> Vec<String> {
+        found.into_iter().collect()
+    }
+
+    #[test]
+    fn finds_words_by_prefix() {
+        let index = index();
+
+        assert_eq!(ids(index.find("tokio")), ["a", "c"]);
+        assert_eq!(ids(index.find("time")), ["a", "b", "c"]);
+        assert_eq!(ids(index.find("TIMEOUT")), ["b", "c"]);
+        assert_eq!(ids(index.find("lifetime")), ["b"]);
+        assert!(index.find("python").is_empty());
+        assert!(index.find("").is_empty());
+    }
+
+    #[test]
+    fn finds_every_word_of_the_query() {
+        let index = index();
+
+        assert_eq!(ids(index.find("tokio timeout")), ["c"]);
+        assert!(index.find("tokio lifetimes").is_empty());
+    }
+
+    #[test]
+    fn updates_changed_conversations() {
+        let mut index = index();
+        assert!(index.add(&conversation("a", None, &["Something else"])));
+
+        assert_eq!(ids(index.find("tokio")), ["c"]);
+        assert_eq!(ids(index.find("something")), ["a"]);
+
+        index.remove("c");
+        assert!(index.find("tokio").is_empty());
+        assert!(!index.words.contains_key("again"));
+    }
+
+    #[test]
+    fn notices_when_the_words_are_the_same() {
+        let mut index = index();
+
+        // The same words in another order, and in another case
+        assert!(!index.add(&conversation(
+            "a",
+            None,
+            &["How do timers work", "in tokio? How"]
+        )));
+        assert!(index.add(&conversation("a", Some("Timers"), &["How do timers work?"])));
+        assert!(index.add(&conversation("d", None, &[])));
+        assert!(!index.add(&conversation("d", None, &[])));
+    }
+
+    #[test]
+    fn shows_the_text_around_a_match() {
+        assert_eq!(snippet("a short\nmessage", 2, 7), "a short message");
+
+        let content = format!("{}needle{}", "x".repeat(50), " y".repeat(30));
+        let start = content.find("needle").unwrap();
+        assert_eq!(
+            snippet(&content, start, start + 6),
+            format!(
+                "...{}needle{}...",
+                "x".repeat(40),
+                " y".repeat(20).trim_end()
+            )
+        );
+    }
+}
@@ -1,248 +1,1819 @@
-use cursive::theme::{Color, PaletteColor, Theme};
-use cursive::view::{Nameable, Resizable, ScrollStrategy};
-use cursive::views::{Dialog, EditView, LinearLayout, Panel, ScrollView, TextView};
-use cursive::Cursive;
+use cursive::event::{Event, EventResult, Key};
+use cursive::reexports::enumset::enum_set;
+use cursive::theme::{BaseColor, Color, ColorStyle, ColorType, Effect, PaletteColor, Style, Theme};
+use cursive::utils::markup::StyledString;
+use cursive::view::{Nameable, Resizable, ScrollStrategy, Scrollable};
+use cursive::views::{
+    Checkbox, Dialog, EditView, HideableView, LinearLayout, NamedView, OnEventView, Panel,
+    ResizedView, ScrollView, SelectView, TextArea, TextView,
+};
+use cursive::{CbSink, Cursive};
 use futures::executor::block_on;
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc::channel;
-use std::time::Duration;
-use std::{env, str, thread};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::{process, str, thread};
 use syntect::dumps::from_binary;
 use syntect::highlighting::{Theme as HighlightingTheme, ThemeSet};
 use syntect::parsing::SyntaxSet;
 
 mod api;
-use api::{stream_chatgpt_response, Message, Role};
+use api::stream_response;
+
+mod attach;
+use attach::{attach, complete_input, mentions, read_attachment, read_image, PendingAttachment};
+
+mod branches;
+use branches::{open_branches, send_branch};
+
+mod commands;
+use commands::{parse_command, Command};
+
+mod compare;
+use compare::{
+    choose_response, compare_responses, parse_targets, show_comparison, update_comparison,
+    Comparison,
+};
+
+mod config;
+use config::{Config, Profile};
+
+mod context;
+use context::prepare_context;
+
+mod index;
 
 mod format;
-use format::format_message;
+use format::{mark_out_of_context, Formatter, StreamingMessage, CODE_CACHE_SIZE};
+
+mod message;
+use message::{Message, MessageMetadata, Role, ToolCall};
+
+mod models;
+use models::{open_model_picker, select_model, switch_profile};
+
+mod patch;
+use patch::open_diffs;
+
+mod queue;
+use queue::{edit_queued_message, remove_queued_message, update_queue};
+
+mod run;
+use run::{open_snippets, run_shell_command};
+
+mod search;
+use search::{
+    close_search, highlight, move_search, open_search, open_session_search, run_search,
+    search_open, Search,
+};
+
+mod session;
+use session::{Conversation, SessionEntry, SessionFilter, SessionMetadata};
+
+mod sidebar;
+use sidebar::{
+    edit_session, load_sessions, sync_session, toggle_pinned, toggle_sidebar, update_sidebar,
+    SIDEBAR_WIDTH,
+};
+
+mod status;
+
+mod tab;
+use tab::{QueuedMessage, Tab};
+
+mod title;
+use title::{generate_title, title_profile};
+
+mod tokens;
+use tokens::{context_limit, count_message_tokens, count_tokens, message_tokens};
+
+mod tools;
+use tools::{confirm_tool_calls, tool_definitions};
+
+mod tree;
+
+mod usage;
+use usage::{conversation_cost, format_cost, usage_report};
 
 #[derive(Serialize, Deserialize)]
 pub enum SystemMessage {
+    /// Waiting for a response
     ResponsePending,
+    /// The response has finished streaming, or failed
+    ResponseDone,
+    /// An error that doesn't affect the messages, such as failing to save the conversation
+    Error(String),
+    /// The number of tokens used by the conversation so far
+    ContextTokens(usize),
+    /// The index of the first message that is still sent to the model
+    ContextStart(usize),
+    /// The title the model wrote for the conversation
+    Title(String),
+    /// A response that was requested in the background, whose usage is recorded
+    BackgroundUsage(MessageMetadata),
+    /// The response called local tools, which need to be approved before the turn continues
+    ToolCalls(Vec<ToolCall>),
+    /// The response to the results of tools failed. Unlike other failed responses, the messages
+    /// before it are kept.
+    ContinuationFailed(String),
 }
 
 pub enum ProcessedMessage {
     SystemMessage(SystemMessage),
-    ChatMessage(Result<Message, String>),
+    ChatMessage(Result<Box<Message>, String>),
+    /// Text that was added to the response that is being streamed
+    ResponseDelta(String),
+    /// An update to the response in a pane of a comparison
+    Comparison(usize, Box<ProcessedMessage>),
 }
 
-fn main() {
-    let mut siv = cursive::default();
+/// A request from the UI to the thread of a conversation
+pub enum Request {
+    /// Send a message and stream the response
+    Send(Message),
+    /// Send a message in place of the message at the index, starting a new branch
+    Edit(usize, Message),
+    /// Make the branch containing the node active
+    Switch(usize),
+    /// Send a message to several profiles at once, to compare their responses
+    Compare(Message, Vec<Profile>),
+    /// Continue the conversation with the chosen response of a comparison, along with the
+    /// index of its pane, or discard the message if none was chosen
+    Choose(Option<(usize, Message)>),
+    /// Set the title of the conversation
+    SetTitle(String),
+    /// Record the usage of a response that was requested in the background
+    AddUsage(MessageMetadata),
+    /// Set the tags, folder and pin of the conversation
+    SetMetadata(SessionMetadata),
+    /// Send the results of the tools that the last response called, to continue the turn
+    ToolResults(Vec<Message>),
+    /// Add a message that is only shown, without sending it
+    Show(Message),
+}
+
+/// UI state, stored as the cursive user data
+pub struct AppState {
+    config: Config,
+    /// The models available for each profile, so they only need to be fetched once
+    models: HashMap<String, Vec<String>>,
+    /// The open conversations
+    tabs: Vec<Tab>,
+    /// The index of the tab that is shown
+    current: usize,
+    /// The ID of the next tab that is opened
+    next_tab_id: usize,
+    /// The ID of the next queued message
+    next_queue_id: usize,
+    formatter: Formatter,
+    /// The matches of the search bar in the current tab, while it is open
+    search: Option<Search>,
+    /// The saved conversations listed in the sidebar
+    sessions: Vec<SessionEntry>,
+    /// The tag or folder that the sidebar is limited to
+    session_filter: Option<SessionFilter>,
+}
 
-    // Create a channel and thread for 1 second ticks
-    let (tick_send, tick_rcv) = channel::<i32>();
+impl AppState {
+    /// The tab that is shown
+    fn tab(&self) -> &Tab {
+        &self.tabs[self.current]
+    }
+
+    fn tab_mut(&mut self) -> &mut Tab {
+        &mut self.tabs[self.current]
+    }
 
-    let _tickhandler = thread::spawn(move || {
-        let mut counter = 0;
+    /// Finds an open tab by its ID, along with whether it is the tab that is shown
+    fn find_tab(&mut self, id: usize) -> Option<(&mut Tab, bool)> {
+        let current = self.current;
 
-        loop {
-            thread::sleep(Duration::from_secs(1));
-            counter = (counter % 4) + 1;
-            tick_send.send(counter).unwrap();
+        self.tabs
+            .iter_mut()
+            .enumerate()
+            .find(|(_, tab)| tab.id == id)
+            .map(|(i, tab)| (tab, i == current))
+    }
+
+    /// The active profile, with the chosen model applied
+    fn profile(&self) -> Profile {
+        let tab = self.tab();
+        let mut profile = self.config.profile(&tab.active_profile).unwrap().to_owned();
+
+        if let Some(model) = &tab.model {
+            profile.set_model(model.to_owned());
         }
-    });
 
-    // Get API key from environment variable
-    let apikey = match env::var("OPENAI_API_KEY") {
-        Ok(apikey) => apikey,
-        _ => panic!("OPENAI_API_KEY is not set. Please set this environment variable to your OpenAI API Key.")
-    };
+        profile
+    }
 
-    let client = surf::Client::new();
-    let mut messages: Vec<Message> = vec![];
+    fn title(&self) -> String {
+        let profile = self.profile();
 
-    let (user_msg_send, user_msg_recv) = channel::<Message>();
-    let (processed_msg_send, processed_msg_recv) = channel::<ProcessedMessage>();
+        match self.tab().title() {
+            Some(title) => format!("{title} - {} ({})", profile.name, profile.model()),
+            None => format!("{} ({})", profile.name, profile.model()),
+        }
+    }
 
-    let _reqhandler = thread::spawn(move || loop {
-        for m in user_msg_recv.try_iter() {
-            messages.push(m.to_owned());
+    /// The context window of the chosen model, in tokens
+    fn context_limit(&self) -> Option<usize> {
+        let profile = self.profile();
+        profile
+            .context_window
+            .or_else(|| context_limit(profile.model()))
+    }
 
-            processed_msg_send
-                .send(ProcessedMessage::ChatMessage(Ok(m)))
-                .unwrap();
+    /// Creates a user message that is sent with the active profile and model
+    fn user_message(&self, content: String) -> Message {
+        self.tab().user_message(content)
+    }
 
-            // Tell the UI that we're waiting for a response from ChatGPT
-            processed_msg_send
-                .send(ProcessedMessage::SystemMessage(
-                    SystemMessage::ResponsePending,
-                ))
-                .unwrap();
+    /// Creates a user message with the files that are mentioned in it with `@path`, or were
+    /// attached with `/attach`, and the images added with `/image`
+    fn message_with_attachments(&self, text: &str) -> Result<Message, String> {
+        let mut attachments = self.tab().attachments.to_owned();
 
-            let chatgpt_response = block_on(stream_chatgpt_response(
-                &client,
-                &apikey,
-                &messages,
-                &processed_msg_send,
-            ));
-
-            match chatgpt_response.to_owned() {
-                Ok(message) => {
-                    messages.push(message);
+        for path in mentions(text) {
+            if !attachments.iter().any(|a| a.path == path) {
+                attachments.push(read_attachment(&path, self.formatter.syntax_set())?);
+            }
+        }
+
+        let mut message = self.user_message(text.to_owned());
+        message.attached = attach(&attachments);
+        message.metadata.attachments = attachments.iter().map(PendingAttachment::info).collect();
+        message.parts = self.tab().images.to_owned();
+        Ok(message)
+    }
+
+    /// Returns a warning if sending the message would exceed the context window
+    fn context_warning(&self, message: &Message) -> Option<String> {
+        let limit = self.context_limit()?;
+        let profile = self.profile();
+        let tokens = self.tab().context_tokens + message_tokens(profile.model(), message);
+
+        (tokens > limit).then(|| {
+            format!(
+                "Sending this message would use about {tokens} tokens, which is more than the {limit} token context window of {}.\n\nSend it anyway?",
+                profile.model()
+            )
+        })
+    }
+
+    /// Formats the message at `index` in the current tab, marking it if it's no longer sent to
+    /// the model
+    fn render_message(&self, index: usize) -> StyledString {
+        let m = &self.tab().messages[index];
+        let mut formatted = self.formatter.format_message(m);
+
+        // System messages are always kept in the context
+        if index < self.tab().context_start && !matches!(m.role, Role::System) {
+            mark_out_of_context(&mut formatted);
+        }
+
+        if let Some(search) = &self.search {
+            let (ranges, current): (Vec<_>, Vec<_>) = search
+                .matches
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.message == index)
+                .map(|(i, m)| (m.range.to_owned(), i == search.current))
+                .unzip();
+
+            if !ranges.is_empty() {
+                let current = current.iter().position(|&current| current);
+                formatted = highlight(&formatted, &ranges, current);
+            }
+        }
+
+        formatted
+    }
+
+    fn status(&mut self) -> StyledString {
+        let profile = self.profile();
+        let tab = self.tab();
+
+        let mut details = vec![match self.context_limit() {
+            Some(limit) => format!("Context: {} / {limit} tokens", tab.context_tokens),
+            None => format!("Context: {} tokens", tab.context_tokens),
+        }];
+
+        if let Some(cost) = conversation_cost(&self.config.prices, tab.messages.all()) {
+            details.push(format!("Cost: {}", format_cost(Some(cost))));
+        }
+
+        if !tab.attachments.is_empty() {
+            let labels = tab
+                .attachments
+                .iter()
+                .map(|a| a.info().label())
+                .collect::<Vec<_>>();
+
+            details.push(format!("Attached: {}", labels.join(", ")));
+        }
+
+        if !tab.images.is_empty() {
+            let labels = tab.images.iter().map(|i| i.label()).collect::<Vec<_>>();
+            details.push(format!("Images: {}", labels.join(", ")));
+        }
+
+        if !tab.compare.is_empty() {
+            let profiles = tab
+                .compare
+                .iter()
+                .map(|profile| format!("{} ({})", profile.name, profile.model()))
+                .collect::<Vec<_>>();
+
+            details.push(format!("Comparing: {}", profiles.join(", ")));
+        }
+
+        self.tab_mut()
+            .status
+            .render(&profile.name, profile.model(), &details)
+    }
+
+    /// Renders the tab bar, highlighting the current tab and tabs with unseen responses
+    fn tab_bar(&self) -> StyledString {
+        let mut tab_bar = StyledString::new();
+
+        for (i, tab) in self.tabs.iter().enumerate() {
+            let label = format!(" {}: {} ", i + 1, tab.label());
+
+            let style = if i == self.current {
+                Style {
+                    effects: enum_set!(Effect::Reverse),
+                    color: ColorStyle::inherit_parent(),
                 }
-                Err(error) => {
-                    messages.pop();
-                    processed_msg_send
-                        .send(ProcessedMessage::ChatMessage(Err(error)))
-                        .unwrap();
+            } else if tab.unseen {
+                Style {
+                    effects: enum_set!(Effect::Bold),
+                    color: ColorStyle::new(BaseColor::Yellow, ColorType::InheritParent),
                 }
-            }
+            } else {
+                Style::none()
+            };
+
+            tab_bar.append_styled(label, style);
+            tab_bar.append_plain(" ");
         }
-    });
+
+        tab_bar
+    }
+}
+
+/// How often the status bar is redrawn while a response is pending or a notification is shown
+const STATUS_FPS: u32 = 10;
+
+/// The most lines that the input box grows to before it scrolls
+const INPUT_HEIGHT: usize = 8;
+
+type MessagesPanel = Panel<ResizedView<ScrollView<NamedView<LinearLayout>>>>;
+
+fn main() {
+    let mut siv = cursive::default();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(1);
+        }
+    };
 
     // Use default terminal colors
     let (theme, syntax_set, code_theme) = theme(&mut siv);
     siv.set_theme(theme);
 
-    let mut runner = siv.try_into_runner().unwrap();
+    siv.set_user_data(AppState {
+        config,
+        models: HashMap::new(),
+        tabs: vec![],
+        current: 0,
+        next_tab_id: 0,
+        next_queue_id: 0,
+        formatter: Formatter::new(syntax_set, code_theme, CODE_CACHE_SIZE),
+        search: None,
+        sessions: vec![],
+        session_filter: None,
+    });
 
     // Render the layout
-    runner.add_fullscreen_layer(
+    siv.add_fullscreen_layer(
         LinearLayout::horizontal()
-            // .child(
-            //     Panel::new(
-            //         LinearLayout::vertical()
-            //             .child(TextView::new("Previous Sessions").h_align(HAlign::Center))
-            //             .child(DummyView.fixed_height(1)),
-            //     )
-            //     .fixed_width(30),
-            // )
+            .child(
+                HideableView::new(
+                    Panel::new(
+                        LinearLayout::vertical()
+                            .child(
+                                SelectView::<Option<SessionFilter>>::new()
+                                    .popup()
+                                    .on_submit(|s, filter: &Option<SessionFilter>| {
+                                        s.with_user_data(|state: &mut AppState| {
+                                            state.session_filter = filter.to_owned();
+                                        });
+
+                                        update_sidebar(s);
+                                    })
+                                    .with_name("session_filter"),
+                            )
+                            .child(
+                                OnEventView::new(
+                                    SelectView::<String>::new()
+                                        .on_submit(|s, id: &String| {
+                                            if let Err(error) = open_session(s, id) {
+                                                show_error(s, error);
+                                            }
+                                        })
+                                        .with_name("sessions"),
+                                )
+                                .on_event('p', toggle_pinned)
+                                .on_event('e', edit_session)
+                                .scrollable()
+                                .full_height(),
+                            ),
+                    )
+                    .title("Sessions")
+                    .fixed_width(SIDEBAR_WIDTH),
+                )
+                .with_name("sidebar"),
+            )
             .child(
                 LinearLayout::vertical()
-                    .child(Panel::new(
-                        ScrollView::new(LinearLayout::vertical().with_name("messages_container"))
+                    .child(TextView::new("").with_name("tab_bar"))
+                    .child(
+                        Panel::new(
+                            ScrollView::new(
+                                LinearLayout::vertical().with_name("messages_container"),
+                            )
                             .scroll_strategy(ScrollStrategy::StickToBottom)
                             .full_height(),
-                    ))
+                        )
+                        .with_name("messages_panel"),
+                    )
+                    .child(
+                        OnEventView::new(
+                            SelectView::<usize>::new()
+                                .on_submit(|s, id| edit_queued_message(s, *id))
+                                .with_name("queue"),
+                        )
+                        .on_event(Key::Del, |s| {
+                            let selected = s
+                                .call_on_name("queue", |view: &mut SelectView<usize>| {
+                                    view.selection()
+                                })
+                                .flatten();
+
+                            if let Some(id) = selected {
+                                remove_queued_message(s, *id);
+                            }
+                        }),
+                    )
+                    .child(
+                        HideableView::new(
+                            LinearLayout::horizontal()
+                                .child(TextView::new("Find: "))
+                                .child(
+                                    OnEventView::new(
+                                        EditView::new()
+                                            .filler(" ")
+                                            .on_edit(|s, _, _| run_search(s))
+                                            .on_submit(|s, _| move_search(s, 1))
+                                            .with_name("search_input"),
+                                    )
+                                    .on_event(Key::Down, |s| move_search(s, 1))
+                                    .on_event(Key::Up, |s| move_search(s, -1))
+                                    .on_event(Key::Esc, close_search)
+                                    .full_width(),
+                                )
+                                .child(TextView::new(" "))
+                                .child(
+                                    Checkbox::new()
+                                        .on_change(|s, _| run_search(s))
+                                        .with_name("search_case"),
+                                )
+                                .child(TextView::new(" Match case "))
+                                .child(
+                                    Checkbox::new()
+                                        .on_change(|s, _| run_search(s))
+                                        .with_name("search_regex"),
+                                )
+                                .child(TextView::new(" Regex "))
+                                .child(TextView::new("").with_name("search_count")),
+                        )
+                        .hidden()
+                        .with_name("search_bar"),
+                    )
+                    .child(TextView::new("").with_name("status_bar"))
                     .child(Panel::new(
-                        EditView::new()
-                            .filler(" ")
-                            .on_submit(move |s, m| {
-                                s.call_on_name("input_box", |view: &mut EditView| {
-                                    view.disable();
-                                    view.set_content("");
-                                });
-
-                                let message = Message {
-                                    role: Role::User,
-                                    content: m.trim().to_owned(),
-                                };
-
-                                user_msg_send.send(message).unwrap();
+                        OnEventView::new(TextArea::new().with_name("input_box"))
+                            // Enter sends the message instead of starting a new line
+                            .on_pre_event(Key::Enter, submit_input)
+                            // Complete the path of an attached file, instead of moving the focus
+                            .on_pre_event_inner(Key::Tab, |view, _| {
+                                let mut view = view.get_mut();
+                                let completed = complete_input(view.get_content())?;
+                                let end = completed.len();
+                                view.set_content(completed);
+                                view.set_cursor(end);
+                                Some(EventResult::Consumed(None))
                             })
-                            .with_name("input_box"),
+                            // Long drafts, like the output of commands, scroll instead
+                            .max_height(INPUT_HEIGHT),
                     ))
                     .full_width(),
             )
             .full_screen(),
     );
 
-    runner.refresh();
+    new_tab(&mut siv);
+    load_sessions(&mut siv);
+    siv.focus_name("input_box").ok();
+
+    siv.add_global_callback(Event::CtrlChar('t'), new_tab);
+    siv.add_global_callback(Event::CtrlChar('w'), close_tab);
+    siv.add_global_callback(Event::CtrlChar('b'), open_branches);
+    siv.add_global_callback(Event::CtrlChar('f'), |s| open_search(s, None));
+    siv.add_global_callback(Event::CtrlChar('o'), toggle_sidebar);
+    siv.add_global_callback(Event::CtrlChar('r'), open_snippets);
+    siv.add_global_callback(Event::Ctrl(Key::Right), |s| cycle_tab(s, 1));
+    siv.add_global_callback(Event::Ctrl(Key::Left), |s| cycle_tab(s, -1));
+
+    // Keep the elapsed time and notifications in the status bar up to date
+    siv.add_global_callback(Event::Refresh, update_status);
+
+    siv.run();
+}
+
+/// Sends the message typed in the input box, or runs the command typed there
+fn submit_input(s: &mut Cursive) {
+    let input = s
+        .call_on_name("input_box", |view: &mut TextArea| {
+            view.get_content().to_owned()
+        })
+        .unwrap();
+
+    if let Some(command) = parse_command(&input) {
+        s.call_on_name("input_box", |view: &mut TextArea| {
+            view.set_content("");
+        });
+
+        match command {
+            Ok(command) => run_command(s, command),
+            Err(error) => show_error(s, error),
+        }
+
+        return;
+    }
+
+    let message = s
+        .with_user_data(|state: &mut AppState| state.message_with_attachments(input.trim()))
+        .unwrap();
+
+    let message = match message {
+        Ok(message) => message,
+        Err(error) => {
+            show_error(s, error);
+            return;
+        }
+    };
+
+    // Warn before sending something that won't fit in the context window
+    let warning = s
+        .with_user_data(|state: &mut AppState| state.context_warning(&message))
+        .unwrap();
+
+    match warning {
+        Some(warning) => s.add_layer(
+            Dialog::text(warning)
+                .title("Context window exceeded")
+                .button("Send anyway", move |s| {
+                    s.pop_layer();
+                    submit_message(s, message.to_owned());
+                })
+                .dismiss_button("Cancel"),
+        ),
+        None => submit_message(s, message),
+    }
+}
+
+/// Starts the threads for a new conversation: one that sends its messages and streams the
+/// responses, and one that delivers the responses to the UI as they arrive.
+/// Returns the sender for the requests of the conversation.
+fn start_conversation(
+    config: &Config,
+    conversation: Conversation,
+    cb_sink: CbSink,
+    tab: usize,
+) -> Sender<Request> {
+    let (request_send, request_recv) = channel::<Request>();
+    let (processed_msg_send, processed_msg_recv) = channel::<ProcessedMessage>();
+
+    let request_config = config.clone();
+
+    thread::spawn(move || {
+        handle_requests(
+            request_config,
+            conversation,
+            request_recv,
+            processed_msg_send,
+        )
+    });
+
+    thread::spawn(move || {
+        for m in processed_msg_recv {
+            let delivered = cb_sink.send(Box::new(move |s| handle_processed_message(s, tab, m)));
+
+            // The UI has quit
+            if delivered.is_err() {
+                break;
+            }
+        }
+    });
+
+    request_send
+}
 
-    let mut pending = false;
+/// Sends the messages of a conversation and streams the responses, until its tab is closed
+fn handle_requests(
+    request_config: Config,
+    mut conversation: Conversation,
+    request_recv: Receiver<Request>,
+    processed_msg_send: Sender<ProcessedMessage>,
+) {
+    let client = surf::Client::new();
+
+    // What the context of each pane of the last comparison added, until a response is chosen
+    let mut panes = vec![];
+
+    // Whether a title is being written, so the turns of a tool call don't request more
+    let titling = Arc::new(AtomicBool::new(false));
+
+    for request in request_recv {
+        // The results of tools are sent along with the response that called them, so they
+        // aren't removed if the rest of the turn fails
+        let (new_messages, continuation) = match request {
+            Request::Send(m) => (vec![m], false),
+            Request::Edit(index, m) => {
+                conversation.rewind(index);
+                (vec![m], false)
+            }
+            Request::ToolResults(results) => (results, true),
+            Request::Switch(node) => {
+                conversation.switch_branch(node);
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                continue;
+            }
+            Request::Compare(m, profiles) => {
+                panes = compare_responses(
+                    &client,
+                    &request_config,
+                    &mut conversation,
+                    m,
+                    &profiles,
+                    &processed_msg_send,
+                );
+                continue;
+            }
+            Request::Choose(choice) => {
+                choose_response(
+                    &request_config,
+                    &mut conversation,
+                    std::mem::take(&mut panes),
+                    choice,
+                    &titling,
+                    &processed_msg_send,
+                );
+                continue;
+            }
+            Request::SetTitle(title) => {
+                conversation.title = Some(title);
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                continue;
+            }
+            Request::AddUsage(response) => {
+                conversation.background_usage.push(response);
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                continue;
+            }
+            Request::SetMetadata(metadata) => {
+                conversation.metadata = metadata;
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                continue;
+            }
+            Request::Show(m) => {
+                conversation.messages.push(m);
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                continue;
+            }
+        };
 
-    while runner.is_running() {
-        runner.step();
+        let Some(last) = new_messages.last() else {
+            continue;
+        };
 
-        for m in processed_msg_recv.try_iter() {
+        // Each message records the profile and model it was sent with, so switching them
+        // only affects the messages sent afterwards
+        let profile_name = last.metadata.profile.to_owned().unwrap_or_default();
+        let model = last.metadata.model.to_owned();
+
+        let profile = request_config.profile(&profile_name).map(|profile| {
+            let mut profile = profile.to_owned();
+
+            if let Some(model) = &model {
+                profile.set_model(model.to_owned());
+            }
+
+            profile
+        });
+
+        add_system_prompt(&mut conversation, profile.as_ref(), &processed_msg_send);
+
+        for m in new_messages {
+            conversation.messages.push(m.to_owned());
+
+            processed_msg_send
+                .send(ProcessedMessage::ChatMessage(Ok(Box::new(m))))
+                .unwrap();
+        }
+
+        // Tell the UI that we're waiting for a response from ChatGPT
+        processed_msg_send
+            .send(ProcessedMessage::SystemMessage(
+                SystemMessage::ResponsePending,
+            ))
+            .unwrap();
+
+        // The messages that were sent, after older messages were trimmed or summarized
+        let mut request: Option<Vec<Message>> = None;
+
+        let chatgpt_response = match &profile {
+            Some(profile) => match block_on(prepare_context(
+                &client,
+                &request_config.context,
+                profile,
+                &mut conversation,
+            )) {
+                Ok((start, messages)) => {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(
+                            SystemMessage::ContextStart(start),
+                        ))
+                        .unwrap();
+
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(
+                            SystemMessage::ContextTokens(count_message_tokens(
+                                profile.model(),
+                                &messages,
+                            )),
+                        ))
+                        .unwrap();
+
+                    let response = block_on(stream_response(
+                        &client,
+                        profile,
+                        &messages,
+                        &tool_definitions(&request_config.tools),
+                        &processed_msg_send,
+                    ));
+
+                    request = Some(messages);
+                    response
+                }
+                Err(error) => Err(error),
+            },
+            None => Err(format!("The profile '{profile_name}' does not exist")),
+        };
+
+        let mut tool_calls = vec![];
+
+        match chatgpt_response.to_owned() {
+            Ok(message) => {
+                tool_calls = message.tool_calls.to_owned();
+                conversation.messages.push(message);
+                conversation.profile = profile_name;
+                conversation.model = model;
+
+                if let Err(error) = conversation.save() {
+                    processed_msg_send
+                        .send(ProcessedMessage::SystemMessage(SystemMessage::Error(error)))
+                        .unwrap();
+                }
+
+                if let Some(profile) = &profile {
+                    title_conversation(
+                        &request_config,
+                        &conversation,
+                        profile,
+                        &titling,
+                        &processed_msg_send,
+                    );
+                }
+            }
+            Err(error) if continuation => {
+                processed_msg_send
+                    .send(ProcessedMessage::SystemMessage(
+                        SystemMessage::ContinuationFailed(error),
+                    ))
+                    .unwrap();
+            }
+            Err(error) => {
+                conversation.messages.pop();
+                processed_msg_send
+                    .send(ProcessedMessage::ChatMessage(Err(error)))
+                    .unwrap();
+            }
+        }
+
+        if let (Some(profile), Some(mut request)) = (&profile, request) {
+            match &chatgpt_response {
+                Ok(message) => request.push(message.to_owned()),
+                Err(_) if continuation => {}
+                Err(_) => {
+                    request.pop();
+                }
+            }
+
+            processed_msg_send
+                .send(ProcessedMessage::SystemMessage(
+                    SystemMessage::ContextTokens(count_message_tokens(profile.model(), &request)),
+                ))
+                .unwrap();
+        }
+
+        // The turn only finishes once the model answers without calling any tools
+        let done = match tool_calls.is_empty() {
+            true => SystemMessage::ResponseDone,
+            false => SystemMessage::ToolCalls(tool_calls),
+        };
+
+        processed_msg_send
+            .send(ProcessedMessage::SystemMessage(done))
+            .unwrap();
+    }
+}
+
+/// Starts the conversation with the profile's system prompt, if it hasn't started yet
+fn add_system_prompt(
+    conversation: &mut Conversation,
+    profile: Option<&Profile>,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) {
+    let Some(profile) = profile else {
+        return;
+    };
+
+    if let (true, Some(system_prompt)) = (
+        conversation.messages.is_empty(),
+        profile.system_prompt.to_owned(),
+    ) {
+        let system_message = Message {
+            role: Role::System,
+            content: system_prompt,
+            metadata: MessageMetadata {
+                profile: Some(profile.name.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        conversation.messages.push(system_message.to_owned());
+
+        processed_msg_send
+            .send(ProcessedMessage::ChatMessage(Ok(Box::new(system_message))))
+            .unwrap();
+    }
+}
+
+/// Asks the model for a title in the background once the first response has arrived, unless
+/// the conversation already has one or one is being written
+fn title_conversation(
+    request_config: &Config,
+    conversation: &Conversation,
+    profile: &Profile,
+    titling: &Arc<AtomicBool>,
+    processed_msg_send: &Sender<ProcessedMessage>,
+) {
+    let user_messages = conversation
+        .messages
+        .iter()
+        .filter(|m| matches!(m.role, Role::User))
+        .count();
+
+    if conversation.title.is_some() || user_messages != 1 {
+        return;
+    }
+
+    let Some(profile) = title_profile(request_config, profile) else {
+        return;
+    };
+
+    if titling.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let messages = conversation.messages.to_vec();
+    let processed_msg_send = processed_msg_send.to_owned();
+    let titling = titling.to_owned();
+
+    // The first user line stays the title if this fails, so errors aren't shown
+    thread::spawn(move || {
+        match block_on(generate_title(&surf::Client::new(), &profile, &messages)) {
+            Ok((title, response)) => {
+                processed_msg_send
+                    .send(ProcessedMessage::SystemMessage(SystemMessage::Title(title)))
+                    .ok();
+                processed_msg_send
+                    .send(ProcessedMessage::SystemMessage(
+                        SystemMessage::BackgroundUsage(response),
+                    ))
+                    .ok();
+            }
+            // A later turn may try again
+            Err(_) => titling.store(false, Ordering::SeqCst),
+        }
+    });
+}
+
+fn handle_processed_message(s: &mut Cursive, tab: usize, m: ProcessedMessage) {
+    match m {
+        ProcessedMessage::SystemMessage(m) => {
             match m {
-                ProcessedMessage::SystemMessage(m) => {
-                    match m {
-                        SystemMessage::ResponsePending => {
-                            // Add a chat loading message
-                            pending = true;
-
-                            runner.call_on_name("messages_container", |view: &mut LinearLayout| {
-                                view.add_child(TextView::new(format_message(
-                                    &syntax_set,
-                                    &code_theme,
-                                    &Message {
-                                        role: Role::Assistant,
-                                        content: "".to_string(),
-                                    },
-                                )));
-                            });
+                SystemMessage::ResponsePending => {
+                    s.with_user_data(|state: &mut AppState| {
+                        if let Some((tab, _)) = state.find_tab(tab) {
+                            tab.status.start_response();
                         }
-                    };
+                    });
+
+                    update_status(s);
+                }
+                SystemMessage::ResponseDone => {
+                    let next = s
+                        .with_user_data(|state: &mut AppState| {
+                            let (tab, current) = state.find_tab(tab)?;
+                            tab.status.finish_response();
+                            tab.streaming = None;
+                            tab.busy = false;
+
+                            (!tab.queue.is_empty()).then(|| (tab.queue.remove(0).message, current))
+                        })
+                        .flatten();
+
+                    update_status(s);
+                    sync_session(s, tab);
+
+                    // Send the next queued message, now that the response has finished
+                    if let Some((next, current)) = next {
+                        if current {
+                            update_queue(s);
+                        }
+
+                        send_message(s, tab, next);
+                    }
                 }
-                ProcessedMessage::ChatMessage(m) => {
-                    match m {
-                        Ok(m) => {
-                            // Re-enable the input box when we receive a response from ChatGPT
-                            if let Role::Assistant = m.role {
-                                runner.call_on_name("input_box", |view: &mut EditView| {
-                                    view.enable();
-                                });
-
-                                runner.call_on_name(
-                                    "messages_container",
-                                    |view: &mut LinearLayout| {
-                                        view.remove_child(view.len() - 1);
-                                    },
-                                );
-
-                                pending = false;
+                SystemMessage::Error(error) => {
+                    show_error(s, error);
+                }
+                SystemMessage::ContinuationFailed(error) => {
+                    show_error(s, error);
+
+                    // The conversation doesn't have the partial response, so it is removed
+                    if tab_is_streaming(s, tab) {
+                        pop_messages(s, tab, 1);
+                    }
+                }
+                SystemMessage::ContextTokens(tokens) => {
+                    s.with_user_data(|state: &mut AppState| {
+                        if let Some((tab, _)) = state.find_tab(tab) {
+                            tab.context_tokens = tokens;
+                        }
+                    });
+
+                    update_status(s);
+                }
+                SystemMessage::ContextStart(start) => {
+                    set_context_start(s, tab, start);
+                }
+                SystemMessage::ToolCalls(calls) => {
+                    // The tab stays busy until the results of the tools have been answered
+                    s.with_user_data(|state: &mut AppState| {
+                        if let Some((tab, _)) = state.find_tab(tab) {
+                            tab.status.finish_response();
+                            tab.streaming = None;
+                        }
+                    });
+
+                    update_status(s);
+                    confirm_tool_calls(s, tab, calls);
+                }
+                SystemMessage::Title(title) => {
+                    // A title the user set while the model was writing one is kept
+                    let current = s
+                        .with_user_data(|state: &mut AppState| {
+                            let (tab, current) = state.find_tab(tab)?;
+
+                            if tab.title.is_some() {
+                                return None;
                             }
 
-                            // Add the message to the message container
-                            runner.call_on_name("messages_container", |view: &mut LinearLayout| {
-                                view.add_child(TextView::new(format_message(
-                                    &syntax_set,
-                                    &code_theme,
-                                    &m,
-                                )));
-                            });
+                            tab.title = Some(title.to_owned());
+                            tab.request_send.send(Request::SetTitle(title)).unwrap();
+                            Some(current)
+                        })
+                        .flatten();
+
+                    if current == Some(true) {
+                        update_title(s);
+                    }
+
+                    update_tab_bar(s);
+                    sync_session(s, tab);
+                }
+                SystemMessage::BackgroundUsage(response) => {
+                    s.with_user_data(|state: &mut AppState| {
+                        if let Some((tab, _)) = state.find_tab(tab) {
+                            tab.request_send.send(Request::AddUsage(response)).unwrap();
                         }
-                        Err(error) => {
-                            // Display error message in a dialog
-                            runner.add_layer(Dialog::new().content(TextView::new(error)).button(
-                                "Ok",
-                                |runner| {
-                                    runner.pop_layer();
-                                },
-                            ));
-
-                            // Remove the last user message (avoids confusion later)
-                            runner.call_on_name("messages_container", |view: &mut LinearLayout| {
-                                for _ in 0..2 {
-                                    view.remove_child(view.len() - 1);
+                    });
+                }
+            };
+        }
+        ProcessedMessage::ChatMessage(m) => {
+            match m {
+                Ok(m) => {
+                    if let Role::Assistant = m.role {
+                        let streaming = s
+                            .with_user_data(|state: &mut AppState| {
+                                let (tab, current) = state.find_tab(tab)?;
+
+                                // Highlight the tab until it is shown
+                                tab.unseen |= !current;
+
+                                if tab.streaming.is_some() {
+                                    return Some(true);
                                 }
-                            });
 
-                            // Re-enable the input box
-                            runner.call_on_name("input_box", |view: &mut EditView| {
-                                view.enable();
-                            });
+                                let model = m.metadata.model.as_deref().unwrap_or_default();
+                                tab.status
+                                    .add_response_tokens(count_tokens(model, &m.content));
+                                tab.streaming = Some(StreamingMessage::default());
+                                Some(false)
+                            })
+                            .flatten();
+
+                        update_tab_bar(s);
+                        update_title(s);
 
-                            pending = false;
+                        // Replace the partial response with the complete message
+                        if streaming == Some(true) {
+                            pop_messages(s, tab, 1);
                         }
                     }
+
+                    // Add the message to the message container
+                    push_message(s, tab, *m);
+                }
+                Err(error) => {
+                    // Display error message in a dialog
+                    show_error(s, error);
+
+                    // Remove the last user message (avoids confusion later), along with
+                    // the partial response if there is one
+                    let count = if tab_is_streaming(s, tab) { 2 } else { 1 };
+                    pop_messages(s, tab, count);
                 }
             }
         }
+        ProcessedMessage::ResponseDelta(delta) => {
+            append_to_response(s, tab, &delta);
+        }
+        ProcessedMessage::Comparison(pane, update) => {
+            update_comparison(s, tab, pane, *update);
+        }
+    }
+}
 
-        for m in tick_rcv.try_iter() {
-            if pending {
-                // Add the message to the message container
-                runner.call_on_name("messages_container", |view: &mut LinearLayout| {
-                    view.remove_child(view.len() - 1);
-                    view.add_child(TextView::new(format_message(
-                        &syntax_set,
-                        &code_theme,
-                        &Message {
-                            role: Role::Assistant,
-                            content: String::from_utf8(vec![b'.'; m as usize]).unwrap(),
-                        },
-                    )));
-                });
+fn run_command(s: &mut Cursive, command: Command) {
+    match command {
+        Command::Profile(Some(name)) => switch_profile(s, &name),
+        Command::Profile(None) => {
+            let (profiles, active_profile) = s
+                .with_user_data(|state: &mut AppState| {
+                    (
+                        state.config.profiles.keys().cloned().collect::<Vec<_>>(),
+                        state.tab().active_profile.to_owned(),
+                    )
+                })
+                .unwrap();
+
+            let mut select = SelectView::new().on_submit(|s, name: &String| {
+                s.pop_layer();
+                switch_profile(s, name);
+            });
+
+            for (i, name) in profiles.iter().enumerate() {
+                select.add_item_str(name);
+
+                if *name == active_profile {
+                    select.set_selection(i);
+                }
             }
+
+            s.add_layer(
+                Dialog::around(select)
+                    .title("Profiles")
+                    .dismiss_button("Cancel"),
+            );
         }
+        Command::Model(Some(model)) => select_model(s, model),
+        Command::Model(None) => open_model_picker(s, false),
+        Command::Usage => {
+            let prices = s
+                .with_user_data(|state: &mut AppState| state.config.prices.to_owned())
+                .unwrap();
 
-        runner.refresh();
+            match usage_report(&prices) {
+                Ok(report) => s.add_layer(
+                    Dialog::around(TextView::new(report).scrollable())
+                        .title("Usage")
+                        .dismiss_button("Ok"),
+                ),
+                Err(error) => show_error(s, error),
+            }
+        }
+        Command::NewTab => new_tab(s),
+        Command::CloseTab => close_tab(s),
+        Command::Tab(number) => {
+            let tabs = s
+                .with_user_data(|state: &mut AppState| state.tabs.len())
+                .unwrap();
+
+            if (1..=tabs).contains(&number) {
+                switch_tab(s, number - 1);
+            } else {
+                show_error(s, format!("There is no tab {number}"));
+            }
+        }
+        Command::Branches => open_branches(s),
+        Command::Find(query) => open_search(s, query),
+        Command::Search(query) => open_session_search(s, query),
+        Command::Attach(Some(path)) => {
+            let result = s
+                .with_user_data(|state: &mut AppState| {
+                    let attachment = read_attachment(&path, state.formatter.syntax_set())?;
+
+                    let tab = state.tab_mut();
+                    tab.attachments.retain(|a| a.path != path);
+                    tab.attachments.push(attachment);
+                    Ok(())
+                })
+                .unwrap();
+
+            match result {
+                Ok(()) => update_status(s),
+                Err(error) => show_error(s, error),
+            }
+        }
+        Command::Attach(None) => {
+            s.with_user_data(|state: &mut AppState| {
+                let tab = state.tab_mut();
+                tab.attachments.clear();
+                tab.status.notify("Removed the attached files".to_string());
+            });
+
+            update_status(s);
+        }
+        Command::Image(Some(source)) => match read_image(&source) {
+            Ok(image) => {
+                s.with_user_data(|state: &mut AppState| state.tab_mut().images.push(image));
+                update_status(s);
+            }
+            Err(error) => show_error(s, error),
+        },
+        Command::Image(None) => {
+            s.with_user_data(|state: &mut AppState| {
+                let tab = state.tab_mut();
+                tab.images.clear();
+                tab.status.notify("Removed the images".to_string());
+            });
+
+            update_status(s);
+        }
+        Command::Shell(command) => s.add_layer(
+            Dialog::text(format!(
+                "Run `{command}`?\n\nIts output is added to your message at the cursor."
+            ))
+            .title("Run command")
+            .button("Run", move |s| {
+                s.pop_layer();
+                run_shell_command(s, command.to_owned());
+            })
+            .dismiss_button("Cancel"),
+        ),
+        Command::Run => open_snippets(s),
+        Command::Apply => open_diffs(s),
+        Command::Title(Some(title)) => set_title(s, title),
+        Command::Title(None) => {
+            let title = s
+                .with_user_data(|state: &mut AppState| state.tab().title())
+                .unwrap()
+                .unwrap_or_default();
+
+            s.add_layer(
+                Dialog::around(
+                    EditView::new()
+                        .content(title)
+                        .on_submit(|s, title| {
+                            s.pop_layer();
+                            set_title(s, title.to_string());
+                        })
+                        .with_name("title_input")
+                        .min_width(40),
+                )
+                .title("Title")
+                .button("Ok", |s| {
+                    let title = s
+                        .call_on_name("title_input", |view: &mut EditView| view.get_content())
+                        .unwrap();
+                    s.pop_layer();
+                    set_title(s, title.to_string());
+                })
+                .dismiss_button("Cancel"),
+            );
+        }
+        Command::Regenerate => {
+            let last = s
+                .with_user_data(|state: &mut AppState| {
+                    let tab = state.tab();
+                    let messages = &tab.messages;
+
+                    (0..messages.len())
+                        .rev()
+                        .find(|&i| matches!(messages[i].role, Role::User))
+                        .map(|i| (i, tab.resent_message(&messages[i])))
+                })
+                .unwrap();
+
+            match last {
+                Some((index, message)) => send_branch(s, index, message),
+                None => show_error(s, "There is no message to regenerate".to_string()),
+            }
+        }
+        Command::Compare(targets) => {
+            let result = s
+                .with_user_data(|state: &mut AppState| {
+                    let profiles = match targets.is_empty() {
+                        true => vec![],
+                        false => parse_targets(&state.config, &targets)?,
+                    };
+
+                    let tab = state.tab_mut();
+                    tab.status.notify(match profiles.len() {
+                        0 => "Stopped comparing".to_string(),
+                        count => format!("Comparing {count} profiles"),
+                    });
+                    tab.compare = profiles;
+                    Ok(())
+                })
+                .unwrap();
+
+            match result {
+                Ok(()) => update_status(s),
+                Err(error) => show_error(s, error),
+            }
+        }
     }
 }
 
+/// Opens a new conversation in a tab, using the profile and model of the current tab
+fn new_tab(s: &mut Cursive) {
+    let conversation = s
+        .with_user_data(|state: &mut AppState| {
+            let mut conversation = match state.tabs.is_empty() {
+                true => Conversation::new(&state.config.default_profile().name),
+                false => Conversation::new(&state.tab().active_profile),
+            };
+
+            conversation.model = state
+                .tabs
+                .get(state.current)
+                .and_then(|t| t.model.to_owned());
+            conversation
+        })
+        .unwrap();
+
+    open_tab(s, conversation);
+}
+
+/// Opens a saved conversation in a tab, or switches to it if it's already open
+fn open_session(s: &mut Cursive, id: &str) -> Result<(), String> {
+    let open = s
+        .with_user_data(|state: &mut AppState| state.tabs.iter().position(|t| t.session == id))
+        .unwrap();
+
+    match open {
+        Some(index) => switch_tab(s, index),
+        None => open_tab(s, Conversation::load(id)?),
+    }
+
+    Ok(())
+}
+
+/// Opens a conversation in a new tab, continuing with the profile and model it was last used
+/// with
+fn open_tab(s: &mut Cursive, conversation: Conversation) {
+    let cb_sink = s.cb_sink().clone();
+
+    let index = s
+        .with_user_data(|state: &mut AppState| {
+            // The profile may have been removed from the config since the conversation was saved
+            let mut profile = state
+                .config
+                .profile(&conversation.profile)
+                .unwrap_or_else(|| state.config.default_profile())
+                .to_owned();
+
+            if let Some(model) = &conversation.model {
+                profile.set_model(model.to_owned());
+            }
+
+            let id = state.next_tab_id;
+            state.next_tab_id += 1;
+
+            let mut tab = Tab::new(
+                id,
+                conversation.id.to_owned(),
+                profile.name.to_owned(),
+                conversation.model.to_owned(),
+                start_conversation(&state.config, conversation.to_owned(), cb_sink, id),
+            );
+
+            if !conversation.messages.is_empty() {
+                tab.context_tokens =
+                    count_message_tokens(profile.model(), &conversation.messages.to_vec());
+            }
+
+            tab.messages = conversation.messages;
+            tab.title = conversation.title;
+            tab.metadata = conversation.metadata;
+
+            state.tabs.push(tab);
+            state.tabs.len() - 1
+        })
+        .unwrap();
+
+    switch_tab(s, index);
+}
+
+/// Closes the current tab, stopping its conversation. Closing the last tab opens a new one.
+fn close_tab(s: &mut Cursive) {
+    let index = s
+        .with_user_data(|state: &mut AppState| {
+            state.tabs.remove(state.current);
+            state.current = state.current.min(state.tabs.len().saturating_sub(1));
+
+            (!state.tabs.is_empty()).then_some(state.current)
+        })
+        .unwrap();
+
+    match index {
+        Some(index) => show_tab(s, index),
+        None => new_tab(s),
+    }
+}
+
+/// Switches to the next (or previous) tab, wrapping around
+fn cycle_tab(s: &mut Cursive, offset: isize) {
+    let index = s
+        .with_user_data(|state: &mut AppState| {
+            (state.current as isize + offset).rem_euclid(state.tabs.len() as isize) as usize
+        })
+        .unwrap();
+
+    switch_tab(s, index);
+}
+
+/// Switches to the tab at `index`, keeping the input of the current tab as its draft
+fn switch_tab(s: &mut Cursive, index: usize) {
+    let draft = s
+        .call_on_name("input_box", |view: &mut TextArea| {
+            view.get_content().to_owned()
+        })
+        .unwrap();
+
+    s.with_user_data(|state: &mut AppState| {
+        if let Some(tab) = state.tabs.get_mut(state.current) {
+            tab.draft = draft;
+        }
+    });
+
+    show_tab(s, index);
+}
+
+/// Shows the conversation of the tab at `index`
+fn show_tab(s: &mut Cursive, index: usize) {
+    let draft = s
+        .with_user_data(|state: &mut AppState| {
+            state.search = None;
+            state.current = index;
+            state.tab_mut().unseen = false;
+            state.tab().draft.to_owned()
+        })
+        .unwrap();
+
+    update_messages(s);
+
+    s.call_on_name("input_box", |view: &mut TextArea| {
+        view.set_content(draft);
+    });
+
+    update_tab_bar(s);
+    update_title(s);
+    update_status(s);
+    update_queue(s);
+    show_comparison(s);
+
+    // Search the conversation that is now shown
+    if search_open(s) {
+        run_search(s);
+    }
+}
+
+/// Renders all messages of the current tab again
+fn update_messages(s: &mut Cursive) {
+    let messages = s
+        .with_user_data(|state: &mut AppState| {
+            (0..state.tab().messages.len())
+                .map(|i| state.render_message(i))
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    s.call_on_name("messages_container", |view: &mut LinearLayout| {
+        view.clear();
+
+        for formatted in messages {
+            view.add_child(TextView::new(formatted));
+        }
+    });
+}
+
+fn update_tab_bar(s: &mut Cursive) {
+    let tab_bar = s
+        .with_user_data(|state: &mut AppState| state.tab_bar())
+        .unwrap();
+
+    s.call_on_name("tab_bar", |view: &mut TextView| {
+        view.set_content(tab_bar);
+    });
+}
+
+/// Updates the status bar, along with the stats of the comparison that is shown
+fn update_status(s: &mut Cursive) {
+    let (status, stats, animated) = s
+        .with_user_data(|state: &mut AppState| {
+            let status = state.status();
+            let tab = state.tab_mut();
+
+            let stats = match &mut tab.comparison {
+                Some(comparison) => comparison.panes.iter_mut().map(|p| p.stats()).collect(),
+                None => vec![],
+            };
+
+            let animated = tab.status.is_animated()
+                || tab.comparison.as_ref().is_some_and(|c| c.is_animated());
+
+            (status, stats, animated)
+        })
+        .unwrap();
+
+    s.call_on_name("status_bar", |view: &mut TextView| {
+        view.set_content(status);
+    });
+
+    for (i, stats) in stats.into_iter().enumerate() {
+        s.call_on_name(&format!("comparison_stats_{i}"), |view: &mut TextView| {
+            view.set_content(stats);
+        });
+    }
+
+    // Only redraw periodically while the status changes over time, so we don't use any CPU
+    // while idle
+    s.set_fps(if animated { STATUS_FPS } else { 0 });
+}
+
+/// Adds a message to the end of a tab's conversation
+fn push_message(s: &mut Cursive, tab: usize, m: Message) {
+    let formatted = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, current) = state.find_tab(tab)?;
+            tab.messages.push(m);
+
+            // Other tabs are rendered when they are shown
+            current.then(|| state.render_message(state.tab().messages.len() - 1))
+        })
+        .flatten();
+
+    if let Some(formatted) = formatted {
+        s.call_on_name("messages_container", |view: &mut LinearLayout| {
+            view.add_child(TextView::new(formatted));
+        });
+    }
+}
+
+/// Adds streamed text to the response, which is the last message of a tab's conversation.
+/// Only the part of the response that is still being written is formatted again.
+fn append_to_response(s: &mut Cursive, tab: usize, delta: &str) {
+    let formatted = s
+        .with_user_data(|state: &mut AppState| {
+            let formatter = &state.formatter;
+            let current = state.current;
+            let (i, tab) = state
+                .tabs
+                .iter_mut()
+                .enumerate()
+                .find(|(_, t)| t.id == tab)?;
+
+            let m = tab.messages.last_mut()?;
+            m.content.push_str(delta);
+
+            let model = m.metadata.model.as_deref().unwrap_or_default();
+            tab.status.add_response_tokens(count_tokens(model, delta));
+
+            if i != current {
+                return None;
+            }
+
+            let streaming = tab.streaming.as_mut()?;
+            Some(streaming.format(formatter, m))
+        })
+        .flatten();
+
+    if let Some(formatted) = formatted {
+        s.call_on_name("messages_container", |view: &mut LinearLayout| {
+            if let Some(text) = view
+                .get_child_mut(view.len() - 1)
+                .and_then(|child| child.downcast_mut::<TextView>())
+            {
+                text.set_content(formatted);
+            }
+        });
+    }
+}
+
+/// Whether part of a response has been shown in a tab
+fn tab_is_streaming(s: &mut Cursive, tab: usize) -> bool {
+    s.with_user_data(|state: &mut AppState| {
+        state
+            .find_tab(tab)
+            .is_some_and(|(tab, _)| tab.status.is_streaming())
+    })
+    .unwrap()
+}
+
+/// Removes the last `count` messages from a tab's conversation
+fn pop_messages(s: &mut Cursive, tab: usize, count: usize) {
+    let current = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, current) = state.find_tab(tab)?;
+            for _ in 0..count {
+                tab.messages.pop();
+            }
+            Some(current)
+        })
+        .flatten();
+
+    if current != Some(true) {
+        return;
+    }
+
+    s.call_on_name("messages_container", |view: &mut LinearLayout| {
+        for _ in 0..count.min(view.len()) {
+            view.remove_child(view.len() - 1);
+        }
+    });
+}
+
+/// Marks the messages of a tab before `start` as no longer being in the context window
+fn set_context_start(s: &mut Cursive, tab: usize, start: usize) {
+    let changed = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, current) = state.find_tab(tab)?;
+            let previous = tab.context_start;
+            tab.context_start = start;
+
+            if !current {
+                return None;
+            }
+
+            // Only the messages between the old and new start need to be rendered again
+            let end = previous.max(start).min(state.tab().messages.len());
+
+            Some(
+                (previous.min(start)..end)
+                    .map(|i| (i, state.render_message(i)))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten()
+        .unwrap_or_default();
+
+    s.call_on_name("messages_container", |view: &mut LinearLayout| {
+        for (i, formatted) in changed {
+            if let Some(text) = view
+                .get_child_mut(i)
+                .and_then(|child| child.downcast_mut::<TextView>())
+            {
+                text.set_content(formatted);
+            }
+        }
+    });
+}
+
+/// Sends a message typed in the input box to the current tab's conversation
+fn submit_message(s: &mut Cursive, message: Message) {
+    s.call_on_name("input_box", |view: &mut TextArea| {
+        view.set_content("");
+    });
+
+    let tab = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab_mut();
+            tab.attachments.clear();
+            tab.images.clear();
+            tab.id
+        })
+        .unwrap();
+
+    send_message(s, tab, message);
+    update_status(s);
+}
+
+/// Sends a message, or queues it if the response to the previous message hasn't finished yet.
+/// If the tab is comparing profiles, the message is sent to each of them.
+fn send_message(s: &mut Cursive, tab: usize, message: Message) {
+    if message.metadata.local {
+        show_message(s, tab, message);
+        return;
+    }
+
+    let queued = s
+        .with_user_data(|state: &mut AppState| {
+            let id = state.next_queue_id;
+            let (tab, current) = state.find_tab(tab)?;
+
+            if tab.busy {
+                tab.queue.push(QueuedMessage { id, message });
+                state.next_queue_id += 1;
+                return Some((true, false));
+            }
+
+            tab.busy = true;
+
+            if tab.compare.is_empty() {
+                tab.request_send.send(Request::Send(message)).unwrap();
+                return Some((false, false));
+            }
+
+            tab.comparison = Some(Comparison::new(&tab.compare));
+            tab.request_send
+                .send(Request::Compare(message, tab.compare.to_owned()))
+                .unwrap();
+            Some((false, current))
+        })
+        .flatten();
+
+    match queued {
+        Some((true, _)) => update_queue(s),
+        Some((false, true)) => show_comparison(s),
+        _ => {}
+    }
+}
+
+/// Adds a message that is only shown to a tab's conversation. It is queued while a response is
+/// streaming, since the response has to stay the last message until it has finished.
+fn show_message(s: &mut Cursive, tab: usize, message: Message) {
+    let shown = s
+        .with_user_data(|state: &mut AppState| {
+            let id = state.next_queue_id;
+            let (tab, _) = state.find_tab(tab)?;
+
+            if tab.busy {
+                tab.queue.push(QueuedMessage { id, message });
+                state.next_queue_id += 1;
+                return Some(None);
+            }
+
+            tab.request_send
+                .send(Request::Show(message.to_owned()))
+                .unwrap();
+            Some(Some(message))
+        })
+        .flatten();
+
+    match shown {
+        Some(Some(message)) => push_message(s, tab, message),
+        Some(None) => {
+            update_queue(s);
+            return;
+        }
+        None => return,
+    }
+
+    // Nothing is streaming, so the messages queued after it can be sent now
+    let next = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, current) = state.find_tab(tab)?;
+            (!tab.busy && !tab.queue.is_empty()).then(|| (tab.queue.remove(0).message, current))
+        })
+        .flatten();
+
+    if let Some((next, current)) = next {
+        if current {
+            update_queue(s);
+        }
+
+        send_message(s, tab, next);
+    }
+}
+
+/// Sets the title of the current conversation, replacing the one the model wrote
+fn set_title(s: &mut Cursive, title: String) {
+    let title = title.trim().to_string();
+
+    if title.is_empty() {
+        show_error(s, "The title can't be empty".to_string());
+        return;
+    }
+
+    let tab = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab_mut();
+            tab.title = Some(title.to_owned());
+            tab.request_send.send(Request::SetTitle(title)).unwrap();
+            tab.id
+        })
+        .unwrap();
+
+    update_title(s);
+    update_tab_bar(s);
+    sync_session(s, tab);
+}
+
+fn update_title(s: &mut Cursive) {
+    let title = s
+        .with_user_data(|state: &mut AppState| state.title())
+        .unwrap();
+
+    s.call_on_name("messages_panel", |view: &mut MessagesPanel| {
+        view.set_title(title);
+    });
+}
+
+fn show_error(s: &mut Cursive, error: String) {
+    s.add_layer(
+        Dialog::new()
+            .content(TextView::new(error))
+            .button("Ok", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
 fn theme(siv: &mut Cursive) -> (Theme, SyntaxSet, HighlightingTheme) {
     let mut theme = siv.current_theme().clone();
     theme.palette[PaletteColor::Background] = Color::TerminalDefault;
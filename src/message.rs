@@ -0,0 +1,370 @@
+use std::borrow::Cow;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum Role {
+    #[default]
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "assistant")]
+    Assistant,
+    /// The result of a tool that the assistant called
+    #[serde(rename = "tool")]
+    Tool,
+}
+
+/// Information about a message that is kept locally and never sent to the API
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MessageMetadata {
+    /// The profile that was active when the message was sent or received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The model that was chosen when the message was sent, or that generated the response.
+    /// For user messages, `None` means the profile's configured model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The content filter categories that stopped the response, if it was filtered (Azure only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_filter: Option<Vec<String>>,
+    /// When the message was sent, or when the response started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Local>>,
+    /// The tokens used to generate a response, as reported by the provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The files that were attached to a user message, whose contents are in its `attached`
+    /// field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Whether the message is only shown in the conversation, and never sent to the model
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub local: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// A file, or the output of a command, that was attached to a message
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    /// The path of the file, or the command that was run
+    pub path: String,
+    pub lines: usize,
+    #[serde(default, skip_serializing_if = "AttachmentKind::is_file")]
+    pub kind: AttachmentKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentKind {
+    #[default]
+    File,
+    Command,
+}
+
+impl AttachmentKind {
+    fn is_file(&self) -> bool {
+        *self == AttachmentKind::File
+    }
+}
+
+impl Attachment {
+    /// How the attachment is shown in the UI
+    pub fn label(&self) -> String {
+        match self.kind {
+            AttachmentKind::File => self.path.to_owned(),
+            AttachmentKind::Command => format!("$ {}", self.path),
+        }
+    }
+
+    /// The line that introduces the attachment in the content that is sent
+    pub fn header(&self) -> String {
+        match self.kind {
+            AttachmentKind::File => format!("\n\nAttached file `{}`:\n", self.path),
+            AttachmentKind::Command => format!("\n\nOutput of `{}`:\n", self.path),
+        }
+    }
+}
+
+/// A call of a local tool that the assistant asked for
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON object, which the model may have gotten wrong
+    pub arguments: String,
+}
+
+/// A part of the content of a message, as the OpenAI API writes it
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageUrl {
+    /// A web address, or a `data:` URL with the encoded image
+    pub url: String,
+    /// How closely the model looks at the image: `low`, `high` or `auto`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ImageUrl {
+    /// The media type and base64 data of an image that is encoded in a `data:` URL
+    pub fn inline_data(&self) -> Option<(&str, &str)> {
+        let (media_type, encoded) = self.url.strip_prefix("data:")?.split_once(',')?;
+        Some((media_type.strip_suffix(";base64")?, encoded))
+    }
+}
+
+impl ContentPart {
+    /// How the part is shown in the UI, without the data of encoded images
+    pub fn label(&self) -> String {
+        match self {
+            ContentPart::Text { text } => text.to_owned(),
+            // Base64 takes four characters for every three bytes
+            ContentPart::ImageUrl { image_url } => match image_url.inline_data() {
+                Some((media_type, encoded)) => format!(
+                    "{media_type}, {} KB",
+                    (encoded.len() * 3 / 4).div_ceil(1024)
+                ),
+                None => image_url.url.to_owned(),
+            },
+        }
+    }
+}
+
+/// A message of a conversation. Messages are read through `SavedMessage`, so conversations
+/// saved before a field was added can still be opened.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(from = "SavedMessage")]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    /// The contents of the files attached to a user message, each in a code block after its
+    /// header. They are sent after the content.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub attached: String,
+    /// The name of the participant, to tell apart several users or assistants
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The parts of the content that follow the text, such as images
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<ContentPart>,
+    /// The tools the assistant called instead of, or along with, answering
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call that a tool message is the result of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    pub metadata: MessageMetadata,
+}
+
+/// The content of a message as it may be saved: text, nothing (for responses that only called
+/// tools), or an array of parts
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// A message as it is read, which also accepts the content as written by the OpenAI API
+#[derive(Deserialize)]
+struct SavedMessage {
+    role: Role,
+    #[serde(default)]
+    content: Option<SavedContent>,
+    #[serde(default)]
+    attached: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    parts: Vec<ContentPart>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    tool_call_id: Option<String>,
+    #[serde(default)]
+    metadata: MessageMetadata,
+}
+
+impl From<SavedMessage> for Message {
+    fn from(saved: SavedMessage) -> Self {
+        // The text at the start of an array of parts becomes the text of the message
+        let (mut content, mut parts) = match saved.content {
+            Some(SavedContent::Text(text)) => (text, vec![]),
+            Some(SavedContent::Parts(parts)) => {
+                let text_parts = parts
+                    .iter()
+                    .take_while(|part| matches!(part, ContentPart::Text { .. }))
+                    .count();
+
+                let text = parts[..text_parts]
+                    .iter()
+                    .map(ContentPart::label)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                (text, parts[text_parts..].to_vec())
+            }
+            None => (String::new(), vec![]),
+        };
+
+        parts.extend(saved.parts);
+
+        // Messages saved before attachments had their own field end with them. The last header
+        // of the first attachment is where they start, since the text may quote it.
+        let mut attached = saved.attached;
+        let legacy_start = saved
+            .metadata
+            .attachments
+            .first()
+            .filter(|_| attached.is_empty())
+            .and_then(|first| content.rfind(&first.header()));
+
+        if let Some(start) = legacy_start {
+            attached = content.split_off(start);
+        }
+
+        Message {
+            role: saved.role,
+            content,
+            attached,
+            name: saved.name,
+            parts,
+            tool_calls: saved.tool_calls,
+            tool_call_id: saved.tool_call_id,
+            metadata: saved.metadata,
+        }
+    }
+}
+
+impl Message {
+    /// The content that is sent to the model, which is the text followed by the attachments
+    pub fn sent_content(&self) -> Cow<'_, str> {
+        match self.attached.is_empty() {
+            true => Cow::Borrowed(&self.content),
+            false => Cow::Owned(format!("{}{}", self.content, self.attached)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_plain_content() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": "Hello",
+            "metadata": { "model": "gpt-4" },
+        }))
+        .unwrap();
+
+        assert!(matches!(message.role, Role::Assistant));
+        assert_eq!(message.content, "Hello");
+        assert_eq!(message.metadata.model.as_deref(), Some("gpt-4"));
+        assert!(message.name.is_none());
+        assert!(message.parts.is_empty());
+        assert!(message.tool_calls.is_empty());
+    }
+
+    #[test]
+    fn reads_parts_name_and_tool_calls() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "user",
+            "name": "alice",
+            "content": [
+                { "type": "text", "text": "What is" },
+                { "type": "text", "text": "this?" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(message.content, "What is\nthis?");
+        assert_eq!(message.name.as_deref(), Some("alice"));
+        assert_eq!(message.parts.len(), 1);
+        assert_eq!(message.parts[0].label(), "image/png, 1 KB");
+
+        let message: Message = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "read_file", "arguments": "{\"path\":\"a\"}" },
+            }],
+        }))
+        .unwrap();
+
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls[0].id, "call_1");
+        assert_eq!(message.tool_calls[0].function.name, "read_file");
+    }
+
+    #[test]
+    fn splits_attachments_from_old_content() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "user",
+            "content": "Why \n\nAttached file `a.rs`:\n?\n\nAttached file `a.rs`:\n```rust\nfn a() {}\n```",
+            "metadata": { "attachments": [{ "path": "a.rs", "lines": 1 }] },
+        }))
+        .unwrap();
+
+        assert_eq!(message.content, "Why \n\nAttached file `a.rs`:\n?");
+        assert_eq!(
+            message.attached,
+            "\n\nAttached file `a.rs`:\n```rust\nfn a() {}\n```"
+        );
+        assert_eq!(
+            message.sent_content(),
+            "Why \n\nAttached file `a.rs`:\n?\n\nAttached file `a.rs`:\n```rust\nfn a() {}\n```"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let saved = json!({
+            "role": "tool",
+            "content": "Hi",
+            "attached": "\n\nOutput of `ls`:\n```\na\nb\n```",
+            "name": "bob",
+            "parts": [{ "type": "image_url", "image_url": { "url": "https://a/b.png", "detail": "low" } }],
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "grep", "arguments": "{}" },
+            }],
+            "tool_call_id": "call_0",
+            "metadata": {
+                "profile": "openai",
+                "usage": { "prompt_tokens": 3, "completion_tokens": 4 },
+                "attachments": [{ "path": "ls", "lines": 2, "kind": "command" }],
+            },
+        });
+
+        let message: Message = serde_json::from_value(saved.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&message).unwrap(), saved);
+    }
+}
@@ -0,0 +1,120 @@
+use std::thread;
+
+use cursive::view::Scrollable;
+use cursive::views::{Dialog, SelectView};
+use cursive::Cursive;
+use futures::executor::block_on;
+
+use crate::api::list_models;
+use crate::{show_error, update_status, update_title, AppState};
+
+/// Shows the models available for the active profile, fetching them if they aren't cached
+/// (or if `refresh` is set)
+pub fn open_model_picker(s: &mut Cursive, refresh: bool) {
+    let (profile, cached_models) = s
+        .with_user_data(|state: &mut AppState| {
+            let profile = state.profile();
+
+            if refresh {
+                state.models.remove(&profile.name);
+            }
+
+            let cached_models = state.models.get(&profile.name).cloned();
+            (profile, cached_models)
+        })
+        .unwrap();
+
+    if let Some(models) = cached_models {
+        show_model_picker(s, models, profile.model());
+        return;
+    }
+
+    let cb_sink = s.cb_sink().clone();
+
+    // Fetch the models in the background so the UI stays responsive
+    thread::spawn(move || {
+        let models = block_on(list_models(&surf::Client::new(), &profile));
+
+        cb_sink
+            .send(Box::new(move |s| match models {
+                Ok(models) => {
+                    s.with_user_data(|state: &mut AppState| {
+                        state
+                            .models
+                            .insert(profile.name.to_owned(), models.to_owned());
+                    });
+
+                    show_model_picker(s, models, profile.model());
+                }
+                Err(error) => show_error(s, error),
+            }))
+            .unwrap();
+    });
+}
+
+fn show_model_picker(s: &mut Cursive, models: Vec<String>, current_model: &str) {
+    if models.is_empty() {
+        show_error(s, "There are no models available".to_string());
+        return;
+    }
+
+    let mut select = SelectView::new().on_submit(|s, model: &String| {
+        s.pop_layer();
+        select_model(s, model.to_owned());
+    });
+
+    for (i, model) in models.iter().enumerate() {
+        select.add_item_str(model);
+
+        if model == current_model {
+            select.set_selection(i);
+        }
+    }
+
+    s.add_layer(
+        Dialog::around(select.scrollable())
+            .title("Models")
+            .button("Refresh", |s| {
+                s.pop_layer();
+                open_model_picker(s, true);
+            })
+            .dismiss_button("Cancel"),
+    );
+}
+
+pub fn switch_profile(s: &mut Cursive, name: &str) {
+    let found = s
+        .with_user_data(|state: &mut AppState| {
+            state.config.profile(name)?;
+
+            // The chosen model most likely doesn't exist for another profile
+            let tab = state.tab_mut();
+            tab.active_profile = name.to_owned();
+            tab.model = None;
+            Some(())
+        })
+        .unwrap();
+
+    match found {
+        Some(()) => {
+            s.with_user_data(|state: &mut AppState| {
+                state.tab_mut().status.notify(format!("Switched to {name}"));
+            });
+
+            update_title(s);
+            update_status(s);
+        }
+        None => show_error(s, format!("The profile '{name}' does not exist")),
+    }
+}
+
+pub fn select_model(s: &mut Cursive, model: String) {
+    s.with_user_data(|state: &mut AppState| {
+        let tab = state.tab_mut();
+        tab.status.notify(format!("Using {model}"));
+        tab.model = Some(model);
+    });
+
+    update_title(s);
+    update_status(s);
+}
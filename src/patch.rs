@@ -0,0 +1,699 @@
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use cursive::theme::{BaseColor, ColorStyle, ColorType, Effect, Style};
+use cursive::utils::markup::StyledString;
+use cursive::view::{Resizable, Scrollable};
+use cursive::views::{Dialog, SelectView, TextView};
+use cursive::Cursive;
+
+use crate::format::{code_blocks, is_diff};
+use crate::message::Role;
+use crate::{show_error, update_status, AppState};
+
+/// A line of a hunk
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// A change to one part of a file
+struct Hunk {
+    /// The `@@ ... @@` line
+    header: String,
+    /// Where the hunk starts in the original file, counting from 1, if the diff says so
+    old_start: Option<usize>,
+    lines: Vec<HunkLine>,
+    /// Whether the original file doesn't end with a newline after the hunk's last line
+    old_missing_newline: bool,
+    /// Whether the changed file doesn't end with a newline after the hunk's last line
+    new_missing_newline: bool,
+}
+
+impl Hunk {
+    /// The lines the hunk expects in the file
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines the hunk replaces them with
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes to one file. A missing path means the file is created or deleted.
+struct FilePatch {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+/// What applying a diff would do to a file
+enum Change {
+    Write {
+        path: PathBuf,
+        content: String,
+        /// The original file, if the file was renamed
+        remove: Option<PathBuf>,
+    },
+    Delete(PathBuf),
+}
+
+/// Whether a hunk applies cleanly
+pub struct HunkCheck {
+    pub header: String,
+    pub applies: bool,
+}
+
+/// The dry run of the changes a diff makes to one file
+pub struct FilePlan {
+    /// The path of the file, as it is shown
+    pub name: String,
+    pub hunks: Vec<HunkCheck>,
+    change: Result<Change, String>,
+}
+
+impl FilePlan {
+    /// Why the changes can't be made to the file, if they can't
+    pub fn error(&self) -> Option<&str> {
+        self.change.as_ref().err().map(String::as_str)
+    }
+}
+
+/// Reads the path from a `---` or `+++` line, without the `a/` or `b/` prefix of git diffs
+fn parse_path(line: &str) -> Option<String> {
+    // A tab separates the timestamp that some tools add
+    let path = line[4..].split('\t').next().unwrap_or_default().trim();
+
+    if path == "/dev/null" || path.is_empty() {
+        return None;
+    }
+
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+
+    Some(path.to_owned())
+}
+
+/// Reads where a hunk starts from its `@@ -12,5 +12,6 @@` header
+fn parse_old_start(header: &str) -> Option<usize> {
+    let old = header.split_whitespace().nth(1)?.strip_prefix('-')?;
+    old.split(',').next()?.parse().ok()
+}
+
+/// Parses a unified diff. Models often get the line counts of hunks wrong, so hunks end at the
+/// first line that can't be part of them instead.
+fn parse_diff(diff: &str) -> Result<Vec<FilePatch>, String> {
+    let lines: Vec<&str> = diff.lines().collect();
+    let mut patches: Vec<FilePatch> = vec![];
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+
+        let file_header = line.starts_with("--- ")
+            && lines
+                .get(i + 1)
+                .is_some_and(|next| next.starts_with("+++ "));
+
+        if file_header {
+            patches.push(FilePatch {
+                old_path: parse_path(line),
+                new_path: parse_path(lines[i + 1]),
+                hunks: vec![],
+            });
+            i += 2;
+            continue;
+        }
+
+        if line.starts_with("@@") {
+            let patch = patches
+                .last_mut()
+                .ok_or("The diff has a hunk before the name of its file")?;
+
+            let mut hunk = Hunk {
+                header: line.to_owned(),
+                old_start: parse_old_start(line),
+                lines: vec![],
+                old_missing_newline: false,
+                new_missing_newline: false,
+            };
+            i += 1;
+
+            while i < lines.len() {
+                let line = lines[i];
+
+                let next_file = line.starts_with("--- ")
+                    && lines
+                        .get(i + 1)
+                        .is_some_and(|next| next.starts_with("+++ "));
+                if next_file || line.starts_with("@@") || line.starts_with("diff ") {
+                    break;
+                }
+
+                match line.chars().next() {
+                    Some('+') => hunk.lines.push(HunkLine::Add(line[1..].to_owned())),
+                    Some('-') => hunk.lines.push(HunkLine::Remove(line[1..].to_owned())),
+                    Some(' ') => hunk.lines.push(HunkLine::Context(line[1..].to_owned())),
+                    // Empty context lines often lose their space
+                    None => hunk.lines.push(HunkLine::Context(String::new())),
+                    // "\ No newline at end of file", about the line before it
+                    Some('\\') => match hunk.lines.last() {
+                        Some(HunkLine::Context(_)) => {
+                            hunk.old_missing_newline = true;
+                            hunk.new_missing_newline = true;
+                        }
+                        Some(HunkLine::Remove(_)) => hunk.old_missing_newline = true,
+                        Some(HunkLine::Add(_)) => hunk.new_missing_newline = true,
+                        None => {}
+                    },
+                    Some(_) => break,
+                }
+
+                i += 1;
+            }
+
+            patch.hunks.push(hunk);
+            continue;
+        }
+
+        i += 1;
+    }
+
+    if patches.is_empty() {
+        return Err("The diff doesn't change any files".to_string());
+    }
+
+    Ok(merge_patches(patches))
+}
+
+/// Merges the changes to the same file, since models sometimes split them into several
+/// sections of the diff, which would otherwise overwrite each other's changes
+fn merge_patches(patches: Vec<FilePatch>) -> Vec<FilePatch> {
+    let mut merged: Vec<FilePatch> = vec![];
+
+    for patch in patches {
+        let same_file = merged
+            .iter_mut()
+            .find(|p| p.old_path == patch.old_path && p.new_path == patch.new_path);
+
+        match same_file {
+            Some(same_file) => {
+                same_file.hunks.extend(patch.hunks);
+
+                // The hunks are applied from the top of the file down
+                same_file.hunks.sort_by_key(|hunk| hunk.old_start);
+            }
+            None => merged.push(patch),
+        }
+    }
+
+    merged
+}
+
+/// Resolves a path in the current directory, refusing paths that lead outside of it
+pub fn resolve(path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(format!("{path} is outside of the current directory"));
+    }
+
+    let cwd = env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .map_err(|e| format!("Could not find the current directory: {e}"))?;
+    let resolved = cwd.join(relative);
+
+    // Symbolic links could still lead outside, so check where the closest existing
+    // ancestor really is
+    let existing = resolved
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .and_then(|ancestor| ancestor.canonicalize().ok());
+
+    match existing {
+        Some(existing) if existing.starts_with(&cwd) => Ok(resolved),
+        _ => Err(format!("{path} is outside of the current directory")),
+    }
+}
+
+/// Finds where the lines of a hunk are in the file, preferring the place closest to where the
+/// diff says they are. Trailing whitespace is ignored if the lines don't match exactly.
+fn find_hunk(file: &[String], old: &[&str], expected: usize) -> Option<usize> {
+    if old.is_empty() {
+        return Some(expected.min(file.len()));
+    }
+
+    let exact = |start: usize| {
+        file[start..start + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(a, b)| a == b)
+    };
+    let loose = |start: usize| {
+        file[start..start + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(a, b)| a.trim_end() == b.trim_end())
+    };
+
+    let closest = |matches: &dyn Fn(usize) -> bool| {
+        (0..=file.len().checked_sub(old.len())?)
+            .filter(|&start| matches(start))
+            .min_by_key(|&start| start.abs_diff(expected))
+    };
+
+    closest(&exact).or_else(|| closest(&loose))
+}
+
+/// Applies the hunks of a patch to the contents of a file, and checks each of them. The file
+/// keeps its line endings, and whether it ends with a newline unless the diff changes that.
+fn apply_hunks(content: &str, hunks: &[Hunk]) -> (String, Vec<HunkCheck>) {
+    let mut file: Vec<String> = content.lines().map(str::to_owned).collect();
+    let mut checks = vec![];
+
+    let line_ending = match content.contains("\r\n") {
+        true => "\r\n",
+        false => "\n",
+    };
+    let mut final_newline = content.is_empty() || content.ends_with('\n');
+
+    // How far the hunks that were applied moved the lines after them
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let old = hunk.old_lines();
+        let expected = hunk
+            .old_start
+            .map(|start| (start.saturating_sub(1) as isize + offset).max(0) as usize)
+            .unwrap_or_default();
+
+        let position = find_hunk(&file, &old, expected);
+
+        if let Some(start) = position {
+            let new: Vec<String> = hunk.new_lines().into_iter().map(str::to_owned).collect();
+            offset += new.len() as isize - old.len() as isize;
+            file.splice(start..start + old.len(), new);
+
+            if hunk.new_missing_newline {
+                final_newline = false;
+            } else if hunk.old_missing_newline {
+                final_newline = true;
+            }
+        }
+
+        checks.push(HunkCheck {
+            header: hunk.header.to_owned(),
+            applies: position.is_some(),
+        });
+    }
+
+    let mut content = file.join(line_ending);
+    if final_newline && !file.is_empty() {
+        content.push_str(line_ending);
+    }
+
+    (content, checks)
+}
+
+/// Works out what a patch would do to its file, without changing it
+fn plan_file(patch: &FilePatch) -> FilePlan {
+    let name = patch
+        .new_path
+        .as_ref()
+        .or(patch.old_path.as_ref())
+        .cloned()
+        .unwrap_or_default();
+
+    let original = match &patch.old_path {
+        Some(path) => resolve(path).and_then(|file| {
+            fs::read_to_string(&file)
+                .map(|content| (Some(file), content))
+                .map_err(|e| format!("Could not read {path}: {e}"))
+        }),
+        None => Ok((None, String::new())),
+    };
+
+    let (old_file, content) = match original {
+        Ok(original) => original,
+        Err(error) => {
+            return FilePlan {
+                name,
+                hunks: vec![],
+                change: Err(error),
+            }
+        }
+    };
+
+    let (new_content, hunks) = apply_hunks(&content, &patch.hunks);
+
+    let change = if hunks.iter().any(|hunk| !hunk.applies) {
+        Err(format!("Some changes to {name} don't apply"))
+    } else {
+        match (&patch.new_path, old_file) {
+            (Some(path), None) if resolve(path).is_ok_and(|path| path.exists()) => {
+                Err(format!("{name} already exists"))
+            }
+            (Some(path), old_file) => resolve(path).map(|path| Change::Write {
+                remove: old_file.filter(|old| *old != path),
+                path,
+                content: new_content,
+            }),
+            (None, Some(old_file)) => Ok(Change::Delete(old_file)),
+            (None, None) => Err("The diff has a file without a name".to_string()),
+        }
+    };
+
+    FilePlan {
+        name,
+        hunks,
+        change,
+    }
+}
+
+/// Checks which hunks of a diff apply to the files in the current directory
+fn plan_diff(diff: &str) -> Result<Vec<FilePlan>, String> {
+    Ok(parse_diff(diff)?.iter().map(plan_file).collect())
+}
+
+/// The temporary file that the new content of `path` is written to before it replaces it
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".apply-tmp");
+    path.with_file_name(name)
+}
+
+/// Makes the changes of a diff that was checked with [`plan_diff`]. Nothing is changed unless
+/// every change applies. The new files are written to temporary files first, which then
+/// replace the originals, so a failed write doesn't leave some files changed. Only a failure
+/// while they are renamed or removed can do that.
+fn apply_plans(plans: &[FilePlan]) -> Result<(), String> {
+    if let Some(error) = plans.iter().find_map(FilePlan::error) {
+        return Err(error.to_owned());
+    }
+
+    let mut written: Vec<PathBuf> = vec![];
+
+    for plan in plans {
+        if let Ok(Change::Write { path, content, .. }) = &plan.change {
+            let temporary = temporary_path(path);
+
+            let result = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&temporary, content))
+                // Keep the permissions of the file it replaces, such as whether it is executable
+                .and_then(|_| match fs::metadata(path) {
+                    Ok(metadata) => fs::set_permissions(&temporary, metadata.permissions()),
+                    Err(_) => Ok(()),
+                });
+
+            if let Err(e) = result {
+                for file in written.iter().chain([&temporary]) {
+                    fs::remove_file(file).ok();
+                }
+
+                return Err(format!("Could not write {}: {e}", plan.name));
+            }
+
+            written.push(temporary);
+        }
+    }
+
+    for plan in plans {
+        match &plan.change {
+            Ok(Change::Write { path, remove, .. }) => {
+                fs::rename(temporary_path(path), path)
+                    .map_err(|e| format!("Could not write {}: {e}", plan.name))?;
+
+                if let Some(remove) = remove {
+                    fs::remove_file(remove)
+                        .map_err(|e| format!("Could not remove {}: {e}", remove.display()))?;
+                }
+            }
+            Ok(Change::Delete(path)) => {
+                fs::remove_file(path)
+                    .map_err(|e| format!("Could not remove {}: {e}", plan.name))?;
+            }
+            Err(error) => return Err(error.to_owned()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the diffs in the responses of the current branch, newest first, to pick one to apply
+pub fn open_diffs(s: &mut Cursive) {
+    let diffs = s
+        .with_user_data(|state: &mut AppState| {
+            state
+                .tab()
+                .messages
+                .iter()
+                .filter(|m| matches!(m.role, Role::Assistant))
+                .flat_map(|m| code_blocks(&m.content))
+                .filter(|block| is_diff(block.info.split_whitespace().next().unwrap_or_default()))
+                .map(|block| block.code)
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    match diffs.len() {
+        0 => show_error(s, "There are no diffs in the responses".to_string()),
+        1 => preview_diff(s, &diffs[0]),
+        _ => {
+            let mut select = SelectView::<String>::new().on_submit(|s, diff: &String| {
+                s.pop_layer();
+                preview_diff(s, diff);
+            });
+
+            for diff in diffs.into_iter().rev() {
+                let files: Vec<&str> = diff
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("+++ "))
+                    .map(|path| path.trim_start_matches("b/"))
+                    .collect();
+
+                select.add_item(format!("diff: {}", files.join(", ")), diff.to_owned());
+            }
+
+            s.add_layer(
+                Dialog::around(select.scrollable().max_height(20))
+                    .title("Apply a diff")
+                    .dismiss_button("Cancel")
+                    .max_width(80),
+            );
+        }
+    }
+}
+
+/// Shows which changes of a diff apply to the files in the current directory, and applies them
+/// once it is confirmed
+fn preview_diff(s: &mut Cursive, diff: &str) {
+    let plans = match plan_diff(diff) {
+        Ok(plans) => plans,
+        Err(error) => {
+            show_error(s, error);
+            return;
+        }
+    };
+
+    let applies = plans.iter().all(|plan| plan.error().is_none());
+    let green = Style::from(ColorStyle::new(BaseColor::Green, ColorType::InheritParent));
+    let red = Style::from(ColorStyle::new(BaseColor::Red, ColorType::InheritParent));
+
+    let mut preview = StyledString::new();
+
+    for plan in &plans {
+        preview.append_styled(format!("{}\n", plan.name), Effect::Bold);
+
+        for hunk in &plan.hunks {
+            match hunk.applies {
+                true => preview.append_styled(format!("  applies      {}\n", hunk.header), green),
+                false => preview.append_styled(format!("  doesn't apply {}\n", hunk.header), red),
+            }
+        }
+
+        if let Some(error) = plan.error() {
+            preview.append_styled(format!("  {error}\n"), red);
+        }
+    }
+
+    let mut dialog = Dialog::around(TextView::new(preview).scrollable()).title(match applies {
+        true => "Apply these changes?",
+        false => "The diff doesn't apply",
+    });
+
+    if applies {
+        dialog.add_button("Apply", move |s| {
+            s.pop_layer();
+            apply_diff(s, &plans);
+        });
+    }
+
+    s.add_layer(dialog.dismiss_button("Cancel").max_width(100));
+}
+
+fn apply_diff(s: &mut Cursive, plans: &[FilePlan]) {
+    if let Err(error) = apply_plans(plans) {
+        show_error(s, error);
+        return;
+    }
+
+    s.with_user_data(|state: &mut AppState| {
+        state.tab_mut().status.notify(match plans.len() {
+            1 => "Changed 1 file".to_string(),
+            count => format!("Changed {count} files"),
+        });
+    });
+
+    update_status(s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn parses_files_and_hunks() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,2 +1,2 @@
+ fn main() {
+-    old();
++    new();
+@@ -10,1 +10,1 @@
+-end
+\\ No newline at end of file
++end
+--- /dev/null
++++ b/notes.txt
+@@ -0,0 +1 @@
++Notes
+";
+
+        let patches = parse_diff(diff).unwrap();
+        assert_eq!(patches.len(), 2);
+
+        assert_eq!(patches[0].old_path.as_deref(), Some("src/a.rs"));
+        assert_eq!(patches[0].new_path.as_deref(), Some("src/a.rs"));
+        assert_eq!(patches[0].hunks.len(), 2);
+        assert_eq!(patches[0].hunks[0].old_start, Some(1));
+        assert_eq!(
+            patches[0].hunks[0].old_lines(),
+            ["fn main() {", "    old();"]
+        );
+        assert_eq!(
+            patches[0].hunks[0].new_lines(),
+            ["fn main() {", "    new();"]
+        );
+        assert!(patches[0].hunks[1].old_missing_newline);
+        assert!(!patches[0].hunks[1].new_missing_newline);
+
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].new_path.as_deref(), Some("notes.txt"));
+    }
+
+    #[test]
+    fn rejects_diffs_without_files() {
+        assert!(parse_diff("just some text").is_err());
+        assert!(parse_diff("@@ -1 +1 @@\n-a\n+b").is_err());
+    }
+
+    #[test]
+    fn finds_the_closest_hunk() {
+        let file = lines("a\nb\nx\na\nb\n");
+
+        assert_eq!(find_hunk(&file, &["a", "b"], 0), Some(0));
+        assert_eq!(find_hunk(&file, &["a", "b"], 4), Some(3));
+        assert_eq!(find_hunk(&file, &["c"], 0), None);
+        assert_eq!(find_hunk(&file, &[], 10), Some(5));
+    }
+
+    #[test]
+    fn finds_hunks_with_different_trailing_whitespace() {
+        let file = lines("one  \ntwo\n");
+
+        assert_eq!(find_hunk(&file, &["one", "two"], 0), Some(0));
+    }
+
+    #[test]
+    fn applies_hunks_keeping_line_endings() {
+        let diff = "\
+--- a/f
++++ b/f
+@@ -1,2 +1,3 @@
+ a
++b
+ c
+@@ -3,1 +4,1 @@
+-d
++e
+";
+        let patches = parse_diff(diff).unwrap();
+        let (content, checks) = apply_hunks("a\r\nc\r\nd\r\n", &patches[0].hunks);
+
+        assert_eq!(content, "a\r\nb\r\nc\r\ne\r\n");
+        assert!(checks.iter().all(|check| check.applies));
+    }
+
+    #[test]
+    fn applies_hunks_to_files_without_a_final_newline() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n";
+        let patches = parse_diff(diff).unwrap();
+
+        let (content, _) = apply_hunks("a", &patches[0].hunks);
+        assert_eq!(content, "b");
+
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n\\ No newline at end of file\n";
+        let patches = parse_diff(diff).unwrap();
+
+        let (content, _) = apply_hunks("a\n", &patches[0].hunks);
+        assert_eq!(content, "b");
+    }
+
+    #[test]
+    fn reports_hunks_that_do_not_apply() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-missing\n+b\n";
+        let patches = parse_diff(diff).unwrap();
+        let (content, checks) = apply_hunks("a\n", &patches[0].hunks);
+
+        assert_eq!(content, "a\n");
+        assert!(!checks[0].applies);
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_current_directory() {
+        assert!(resolve("src/main.rs").is_ok());
+        assert!(resolve("./new/file.txt").is_ok());
+        assert!(resolve("../outside").is_err());
+        assert!(resolve("src/../../outside").is_err());
+        assert!(resolve("/etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use cursive::reexports::enumset::enum_set;
+use cursive::theme::{ColorStyle, Effect, Style};
+use cursive::utils::markup::StyledString;
+use cursive::view::{Nameable, Resizable};
+use cursive::views::{Dialog, EditView, SelectView};
+use cursive::Cursive;
+
+use crate::AppState;
+
+/// Shows the queued messages below the conversation
+pub fn update_queue(s: &mut Cursive) {
+    let items = s
+        .with_user_data(|state: &mut AppState| {
+            state
+                .tab()
+                .queue
+                .iter()
+                .map(|queued| {
+                    let content = queued.message.content.trim();
+                    let mut lines = content.lines();
+                    let first_line = lines.next().unwrap_or_default();
+                    let more = if lines.next().is_some() { " ..." } else { "" };
+
+                    (format!("Queued: {first_line}{more}"), queued.id)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    s.call_on_name("queue", |view: &mut SelectView<usize>| {
+        let selected = view.selected_id();
+
+        view.clear();
+        for (label, id) in items {
+            view.add_item(
+                StyledString::styled(
+                    label,
+                    Style {
+                        effects: enum_set!(Effect::Dim | Effect::Italic),
+                        color: ColorStyle::inherit_parent(),
+                    },
+                ),
+                id,
+            );
+        }
+
+        if let Some(selected) = selected {
+            view.set_selection(selected.min(view.len().saturating_sub(1)));
+        }
+    });
+
+    // The empty queue can't keep the focus
+    let empty = s
+        .with_user_data(|state: &mut AppState| state.tab().queue.is_empty())
+        .unwrap();
+
+    if empty {
+        s.focus_name("input_box").ok();
+    }
+}
+
+/// Opens a dialog to edit or remove a queued message
+pub fn edit_queued_message(s: &mut Cursive, id: usize) {
+    let content = s
+        .with_user_data(|state: &mut AppState| {
+            state
+                .tab()
+                .queue
+                .iter()
+                .find(|queued| queued.id == id)
+                .map(|queued| queued.message.content.to_owned())
+        })
+        .flatten();
+
+    // The message may have been sent in the meantime
+    let Some(content) = content else {
+        return;
+    };
+
+    s.add_layer(
+        Dialog::around(
+            EditView::new()
+                .content(content)
+                .with_name("queue_edit")
+                .min_width(60),
+        )
+        .title("Queued message")
+        .button("Save", move |s| {
+            let content = s
+                .call_on_name("queue_edit", |view: &mut EditView| view.get_content())
+                .unwrap();
+
+            s.pop_layer();
+
+            if content.trim().is_empty() {
+                remove_queued_message(s, id);
+                return;
+            }
+
+            s.with_user_data(|state: &mut AppState| {
+                let queue = &mut state.tab_mut().queue;
+
+                if let Some(queued) = queue.iter_mut().find(|queued| queued.id == id) {
+                    queued.message.content = content.trim().to_owned();
+                }
+            });
+
+            update_queue(s);
+        })
+        .button("Remove", move |s| {
+            s.pop_layer();
+            remove_queued_message(s, id);
+        })
+        .dismiss_button("Cancel"),
+    );
+}
+
+pub fn remove_queued_message(s: &mut Cursive, id: usize) {
+    s.with_user_data(|state: &mut AppState| {
+        state.tab_mut().queue.retain(|queued| queued.id != id);
+    });
+
+    update_queue(s);
+}
@@ -0,0 +1,494 @@
+use std::env;
+use std::fs;
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use cursive::view::{Nameable, Resizable, Scrollable};
+use cursive::views::{Checkbox, Dialog, LinearLayout, SelectView, TextArea, TextView};
+use cursive::Cursive;
+
+use crate::attach::{exit_status, fence, output_text};
+use crate::format::code_blocks;
+use crate::message::{Message, MessageMetadata, Role};
+use crate::{send_message, show_error, update_status, AppState};
+
+/// How long a code block or command may run before it is stopped
+pub const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Numbers the working directories of the code blocks that are run
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// The languages of the code blocks that can be run
+#[derive(Clone, Copy)]
+pub enum Language {
+    Shell,
+    Python,
+}
+
+impl Language {
+    /// The language of a code block, from the first word of its info string
+    pub fn from_info(info: &str) -> Option<Language> {
+        match info.split_whitespace().next()?.to_lowercase().as_str() {
+            "sh" | "bash" | "shell" | "zsh" => Some(Language::Shell),
+            "python" | "python3" | "py" => Some(Language::Python),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::Shell => "shell",
+            Language::Python => "Python",
+        }
+    }
+
+    /// The interpreter that runs the code, and the file the code is written to
+    fn interpreter(&self) -> (&'static str, &'static str) {
+        match self {
+            Language::Shell => ("sh", "snippet.sh"),
+            Language::Python => ("python3", "snippet.py"),
+        }
+    }
+}
+
+/// A code block from a response that can be run
+#[derive(Clone)]
+pub struct Snippet {
+    pub language: Language,
+    pub code: String,
+}
+
+/// Runs a code block in a new temporary directory, and returns its output followed by how it
+/// ended. The code can't read input, only sees the `PATH` environment variable (so API keys
+/// aren't exposed to it), and is stopped if it runs for too long.
+fn run_snippet(snippet: &Snippet) -> Result<String, String> {
+    let dir = env::temp_dir().join(format!(
+        "chatgpt-tui-run-{}-{}",
+        process::id(),
+        NEXT_RUN.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+
+    let result = run_in(&dir, snippet);
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+fn run_in(dir: &std::path::Path, snippet: &Snippet) -> Result<String, String> {
+    let (interpreter, file) = snippet.language.interpreter();
+
+    fs::write(dir.join(file), &snippet.code)
+        .map_err(|e| format!("Could not write the code to {}: {e}", dir.display()))?;
+
+    let mut command = Command::new(interpreter);
+    command
+        .arg(file)
+        .current_dir(dir)
+        .env_clear()
+        .env("PATH", env::var_os("PATH").unwrap_or_default())
+        .env("HOME", dir)
+        .env("TMPDIR", dir);
+
+    run_process(command, RUN_TIMEOUT)
+}
+
+/// Runs a process without input, and returns its output followed by how it ended. The process
+/// is stopped if it runs longer than `timeout`, and the processes it started are stopped along
+/// with it.
+pub fn run_process(mut command: Command, timeout: Duration) -> Result<String, String> {
+    let program = command.get_program().to_string_lossy().to_string();
+
+    // Start it in a process group of its own, which the processes it starts also belong to
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run {program}: {e}"))?;
+
+    // Read the output while the code runs, so it doesn't block on a full pipe
+    let stdout = Arc::new(Mutex::new(vec![]));
+    let stderr = Arc::new(Mutex::new(vec![]));
+    let readers = [
+        read_output(child.stdout.take(), &stdout),
+        read_output(child.stderr.take(), &stderr),
+    ];
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break Some(status);
+        }
+
+        if start.elapsed() > timeout {
+            break None;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    // Processes that it left running in the background would keep the pipes open
+    stop_process_group(&mut child);
+    child.wait().ok();
+
+    for reader in readers {
+        reader.join().ok();
+    }
+
+    let status = match status {
+        Some(status) => exit_status(status),
+        None => format!("[stopped after {} seconds]", timeout.as_secs_f64()),
+    };
+
+    let stdout = stdout.lock().unwrap();
+    let stderr = stderr.lock().unwrap();
+    Ok(output_text(&stdout, &stderr, &status))
+}
+
+/// Stops a process that was started in its own process group, along with every process it
+/// started
+fn stop_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: `kill` only sends a signal, and the negative ID addresses the process group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    #[cfg(not(unix))]
+    child.kill().ok();
+}
+
+/// Collects the output of a pipe in a thread
+fn read_output(
+    pipe: Option<impl Read + Send + 'static>,
+    output: &Arc<Mutex<Vec<u8>>>,
+) -> thread::JoinHandle<()> {
+    let output = output.to_owned();
+
+    thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+
+        let mut buffer = [0; 4096];
+        while let Ok(read) = pipe.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+
+            output.lock().unwrap().extend_from_slice(&buffer[..read]);
+        }
+    })
+}
+
+/// Runs a command with the shell, and returns its output followed by its exit status
+fn command_output(command: &str) -> Result<String, String> {
+    let mut shell = match cfg!(windows) {
+        true => Command::new("cmd"),
+        false => Command::new("sh"),
+    };
+
+    shell
+        .arg(if cfg!(windows) { "/C" } else { "-c" })
+        .arg(command);
+
+    run_process(shell, RUN_TIMEOUT)
+}
+
+/// Inserts a block into a draft at `cursor`, on lines of its own. Returns the new draft and the
+/// position after the block.
+fn insert_block(draft: &str, cursor: usize, block: &str) -> (String, usize) {
+    let (before, after) = draft.split_at(cursor);
+    let mut inserted = before.to_owned();
+
+    if !before.is_empty() && !before.ends_with('\n') {
+        inserted.push('\n');
+    }
+
+    inserted.push_str(block);
+    inserted.push('\n');
+
+    let cursor = inserted.len();
+    inserted.push_str(after.strip_prefix('\n').unwrap_or(after));
+    (inserted, cursor)
+}
+
+/// Runs a shell command in the background, and inserts its output into the input of the tab
+/// that ran it, as a code block at the cursor, once it has finished
+pub fn run_shell_command(s: &mut Cursive, command: String) {
+    let tab = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab_mut();
+            tab.status.notify(format!("Running `{command}`"));
+            tab.id
+        })
+        .unwrap();
+
+    update_status(s);
+
+    let cb_sink = s.cb_sink().clone();
+
+    thread::spawn(move || {
+        let output = command_output(&command);
+
+        cb_sink
+            .send(Box::new(move |s| {
+                let output = match output {
+                    Ok(output) => output,
+                    Err(error) => {
+                        show_error(s, error);
+                        return;
+                    }
+                };
+
+                let fence = fence(&output);
+                let block = format!("Output of `{command}`:\n{fence}\n{output}\n{fence}");
+
+                let current = s
+                    .with_user_data(|state: &mut AppState| {
+                        let (tab, current) = state.find_tab(tab)?;
+                        tab.status.notify(format!("`{command}` has finished"));
+
+                        // Other tabs keep their input as a draft until they are shown
+                        if !current {
+                            tab.draft = insert_block(&tab.draft, tab.draft.len(), &block).0;
+                        }
+
+                        Some(current)
+                    })
+                    .flatten();
+
+                if current == Some(true) {
+                    s.call_on_name("input_box", |view: &mut TextArea| {
+                        let (draft, cursor) =
+                            insert_block(view.get_content(), view.cursor(), &block);
+                        view.set_content(draft);
+                        view.set_cursor(cursor);
+                    });
+                }
+
+                update_status(s);
+            }))
+            .ok();
+    });
+}
+
+/// Lists the shell and Python code blocks in the responses of the current branch, newest
+/// first, to pick one to run
+pub fn open_snippets(s: &mut Cursive) {
+    let snippets = s
+        .with_user_data(|state: &mut AppState| {
+            state
+                .tab()
+                .messages
+                .iter()
+                .filter(|m| matches!(m.role, Role::Assistant))
+                .flat_map(|m| code_blocks(&m.content))
+                .filter_map(|block| {
+                    Some(Snippet {
+                        language: Language::from_info(&block.info)?,
+                        code: block.code,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+    if snippets.is_empty() {
+        show_error(
+            s,
+            "There are no shell or Python code blocks in the responses".to_string(),
+        );
+        return;
+    }
+
+    let mut select = SelectView::<Snippet>::new().on_submit(|s, snippet: &Snippet| {
+        s.pop_layer();
+        confirm_snippet(s, snippet.to_owned());
+    });
+
+    for snippet in snippets.into_iter().rev() {
+        let first_line = snippet
+            .code
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default()
+            .trim()
+            .to_owned();
+
+        select.add_item(
+            format!("{}: {first_line}", snippet.language.name()),
+            snippet,
+        );
+    }
+
+    s.add_layer(
+        Dialog::around(select.scrollable().max_height(20))
+            .title("Run a code block")
+            .dismiss_button("Cancel")
+            .max_width(80),
+    );
+}
+
+/// Shows the code that is about to be run, and runs it once it is confirmed
+fn confirm_snippet(s: &mut Cursive, snippet: Snippet) {
+    let language = snippet.language.name();
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(
+                    TextView::new(snippet.code.trim_end())
+                        .scrollable()
+                        .max_height(15),
+                )
+                .child(TextView::new(" "))
+                .child(
+                    LinearLayout::horizontal()
+                        .child(Checkbox::new().with_name("run_send"))
+                        .child(TextView::new(" Send the output to the model")),
+                ),
+        )
+        .title(format!("Run this {language} code?"))
+        .button("Run", move |s| {
+            let send = s
+                .call_on_name("run_send", |view: &mut Checkbox| view.is_checked())
+                .unwrap();
+
+            s.pop_layer();
+            run_code(s, snippet.to_owned(), send);
+        })
+        .dismiss_button("Cancel")
+        .max_width(80),
+    );
+}
+
+/// Runs a code block in the background. Its output is sent to the model as the next message
+/// of the current tab if `send` is set, and only shown in the conversation otherwise.
+fn run_code(s: &mut Cursive, snippet: Snippet, send: bool) {
+    let language = snippet.language.name();
+
+    let tab = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tab_mut();
+            tab.status
+                .notify(format!("Running the {language} code block"));
+            tab.id
+        })
+        .unwrap();
+
+    update_status(s);
+
+    let cb_sink = s.cb_sink().clone();
+
+    thread::spawn(move || {
+        let output = run_snippet(&snippet);
+
+        cb_sink
+            .send(Box::new(move |s| match output {
+                Ok(output) => send_output(s, tab, language, &output, send),
+                Err(error) => show_error(s, error),
+            }))
+            .ok();
+    });
+}
+
+/// Adds the output of a code block to a tab's conversation, as a message that is sent to the
+/// model if `send` is set, and only shown otherwise
+fn send_output(s: &mut Cursive, tab: usize, language: &str, output: &str, send: bool) {
+    let fence = fence(output);
+    let block = format!("{fence}\n{output}\n{fence}");
+
+    let message = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, _) = state.find_tab(tab)?;
+
+            Some(match send {
+                true => tab.user_message(format!("Output of the {language} code block:\n{block}")),
+                // Shown messages are labelled as output already
+                false => Message {
+                    role: Role::System,
+                    content: format!("From the {language} code block:\n{block}"),
+                    metadata: MessageMetadata {
+                        created_at: Some(Local::now()),
+                        local: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            })
+        })
+        .flatten();
+
+    match message {
+        Some(message) => send_message(s, tab, message),
+        None => show_error(s, "The tab that ran the code was closed".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn inserts_blocks_on_lines_of_their_own() {
+        assert_eq!(insert_block("", 0, "out"), ("out\n".to_string(), 4));
+        assert_eq!(
+            insert_block("Why? Thanks", 5, "out"),
+            ("Why? \nout\nThanks".to_string(), 10)
+        );
+        assert_eq!(
+            insert_block("Why?\nThanks", 5, "out"),
+            ("Why?\nout\nThanks".to_string(), 9)
+        );
+        assert_eq!(
+            insert_block("Why?\n", 4, "out"),
+            ("Why?\nout\n".to_string(), 9)
+        );
+    }
+
+    #[test]
+    fn runs_shell_commands() {
+        let output = command_output("echo one; echo two >&2; exit 3").unwrap();
+        assert_eq!(output, "one\ntwo\n[exit status 3]");
+    }
+
+    #[test]
+    fn stops_processes_left_in_the_background() {
+        let start = Instant::now();
+        let output = run_process(shell("sleep 20 & echo started"), RUN_TIMEOUT).unwrap();
+
+        assert_eq!(output, "started\n[exit status 0]");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn stops_processes_that_run_too_long() {
+        let start = Instant::now();
+        let output = run_process(
+            shell("echo started; sleep 5; echo finished"),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+
+        assert_eq!(output, "started\n[stopped after 0.2 seconds]");
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,515 @@
+use std::ops::Range;
+
+use cursive::reexports::enumset::enum_set;
+use cursive::theme::{BaseColor, Color, ColorStyle, Effect, Style};
+use cursive::utils::lines::spans::LinesIterator;
+use cursive::utils::markup::StyledString;
+use cursive::view::{Nameable, Resizable, ScrollStrategy, Scrollable};
+use cursive::views::{
+    Checkbox, Dialog, EditView, HideableView, LinearLayout, SelectView, TextView,
+};
+use cursive::Cursive;
+use regex::{Regex, RegexBuilder};
+
+use crate::index::{search_sessions, SessionMatch};
+use crate::{open_session, show_error, update_messages, AppState, MessagesPanel};
+
+/// A match in the rendered text of a message
+pub struct Match {
+    /// The index of the message in the conversation
+    pub message: usize,
+    pub range: Range<usize>,
+}
+
+/// The state of the search bar
+pub struct Search {
+    pub matches: Vec<Match>,
+    /// The index of the match that was jumped to
+    pub current: usize,
+}
+
+/// Builds the pattern for a query, which is matched literally unless `regex` is set
+pub fn pattern(query: &str, case_sensitive: bool, regex: bool) -> Result<Regex, String> {
+    let query = match regex {
+        true => query.to_owned(),
+        false => regex::escape(query),
+    };
+
+    RegexBuilder::new(&query)
+        .case_insensitive(!case_sensitive)
+        .build()
+        .map_err(|e| format!("Invalid regex: {e}"))
+}
+
+/// The text of a rendered message, as it is shown
+pub fn text(formatted: &StyledString) -> String {
+    formatted.spans().map(|span| span.content).collect()
+}
+
+/// Finds the non-empty matches of `pattern` in a rendered message
+pub fn find(pattern: &Regex, formatted: &StyledString) -> Vec<Range<usize>> {
+    pattern
+        .find_iter(&text(formatted))
+        .filter(|m| !m.range().is_empty())
+        .map(|m| m.range())
+        .collect()
+}
+
+/// Restyles the matches in a rendered message. The match at `current` stands out from the rest.
+pub fn highlight(
+    formatted: &StyledString,
+    matches: &[Range<usize>],
+    current: Option<usize>,
+) -> StyledString {
+    let match_style = Style::from(Effect::Reverse);
+    let current_style = Style::from(ColorStyle::new(
+        Color::Dark(BaseColor::Black),
+        Color::Dark(BaseColor::Yellow),
+    ));
+
+    let mut highlighted = StyledString::new();
+    let mut offset = 0;
+
+    for span in formatted.spans() {
+        let end = offset + span.content.len();
+        let mut position = offset;
+
+        // Split the span at the boundaries of the matches that overlap it
+        while position < end {
+            let overlapping = matches
+                .iter()
+                .enumerate()
+                .find(|(_, m)| m.start <= position && position < m.end);
+
+            let (until, style) = match overlapping {
+                Some((i, m)) => {
+                    let style = match current == Some(i) {
+                        true => current_style,
+                        false => match_style,
+                    };
+
+                    (m.end.min(end), span.attr.combine(style))
+                }
+                None => {
+                    let next = matches
+                        .iter()
+                        .map(|m| m.start)
+                        .filter(|&start| start > position)
+                        .min()
+                        .unwrap_or(end);
+
+                    (next.min(end), *span.attr)
+                }
+            };
+
+            highlighted.append_styled(&span.content[position - offset..until - offset], style);
+            position = until;
+        }
+
+        offset = end;
+    }
+
+    highlighted
+}
+
+/// The number of rows a rendered message takes up at `width` columns, and the row that
+/// contains the text at `position`
+pub fn rows(formatted: &StyledString, width: usize, position: usize) -> (usize, usize) {
+    let span_starts: Vec<usize> = formatted
+        .spans()
+        .scan(0, |offset, span| {
+            let start = *offset;
+            *offset += span.content.len();
+            Some(start)
+        })
+        .collect();
+
+    let rows: Vec<_> = LinesIterator::new(formatted, width.max(1)).collect();
+
+    let row = rows
+        .iter()
+        .position(|row| {
+            row.segments
+                .iter()
+                .any(|segment| span_starts[segment.span_id] + segment.end > position)
+        })
+        .unwrap_or(rows.len().saturating_sub(1));
+
+    (rows.len(), row)
+}
+
+/// Shows the search bar, searching for `query` if it is given
+pub fn open_search(s: &mut Cursive, query: Option<String>) {
+    s.call_on_name("search_bar", |view: &mut HideableView<LinearLayout>| {
+        view.unhide();
+    });
+
+    if let Some(query) = query {
+        s.call_on_name("search_input", |view: &mut EditView| {
+            view.set_content(query);
+        });
+    }
+
+    s.focus_name("search_input").ok();
+    run_search(s);
+}
+
+pub fn search_open(s: &mut Cursive) -> bool {
+    s.call_on_name("search_bar", |view: &mut HideableView<LinearLayout>| {
+        view.is_visible()
+    })
+    .unwrap()
+}
+
+/// Hides the search bar along with the highlighted matches
+pub fn close_search(s: &mut Cursive) {
+    s.call_on_name("search_bar", |view: &mut HideableView<LinearLayout>| {
+        view.hide();
+    });
+
+    s.with_user_data(|state: &mut AppState| {
+        state.search = None;
+    });
+
+    // Follow the end of the conversation again, which jumping to a match stopped
+    s.call_on_name("messages_panel", |panel: &mut MessagesPanel| {
+        let scroll = panel.get_inner_mut().get_inner_mut();
+        scroll.set_scroll_strategy(ScrollStrategy::StickToBottom);
+    });
+
+    update_messages(s);
+    s.focus_name("input_box").ok();
+}
+
+/// Searches the messages of the current tab for the text in the search bar, and jumps to the
+/// first match
+pub fn run_search(s: &mut Cursive) {
+    let query = s
+        .call_on_name("search_input", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let case_sensitive = s
+        .call_on_name("search_case", |view: &mut Checkbox| view.is_checked())
+        .unwrap();
+    let regex = s
+        .call_on_name("search_regex", |view: &mut Checkbox| view.is_checked())
+        .unwrap();
+
+    let result: Result<(), String> = s
+        .with_user_data(|state: &mut AppState| {
+            // Search the messages without the highlights of the previous search
+            state.search = None;
+
+            if query.is_empty() {
+                return Ok(());
+            }
+
+            let pattern = pattern(&query, case_sensitive, regex)?;
+            let matches = (0..state.tab().messages.len())
+                .flat_map(|i| {
+                    find(&pattern, &state.render_message(i))
+                        .into_iter()
+                        .map(move |range| Match { message: i, range })
+                })
+                .collect();
+
+            state.search = Some(Search {
+                matches,
+                current: 0,
+            });
+            Ok(())
+        })
+        .unwrap();
+
+    update_messages(s);
+
+    match result {
+        Ok(()) => jump_to_match(s),
+        Err(error) => {
+            s.call_on_name("search_count", |view: &mut TextView| {
+                view.set_content(error);
+            });
+        }
+    }
+}
+
+/// Jumps to the next (or previous) match, wrapping around
+pub fn move_search(s: &mut Cursive, offset: isize) {
+    let changed = s
+        .with_user_data(|state: &mut AppState| {
+            let search = state.search.as_mut()?;
+
+            if search.matches.is_empty() {
+                return None;
+            }
+
+            let previous = search.matches[search.current].message;
+            search.current = (search.current as isize + offset)
+                .rem_euclid(search.matches.len() as isize) as usize;
+            let current = search.matches[search.current].message;
+
+            // Only the messages of the previous and current match change
+            Some(
+                [previous, current]
+                    .into_iter()
+                    .map(|i| (i, state.render_message(i)))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten()
+        .unwrap_or_default();
+
+    s.call_on_name("messages_container", |view: &mut LinearLayout| {
+        for (i, formatted) in changed {
+            if let Some(text) = view
+                .get_child_mut(i)
+                .and_then(|child| child.downcast_mut::<TextView>())
+            {
+                text.set_content(formatted);
+            }
+        }
+    });
+
+    jump_to_match(s);
+}
+
+/// Scrolls the conversation to the current match, and shows the number of matches
+fn jump_to_match(s: &mut Cursive) {
+    let current = s
+        .with_user_data(|state: &mut AppState| {
+            let search = state.search.as_ref()?;
+            let count = search.matches.len();
+
+            Some((
+                search
+                    .matches
+                    .get(search.current)
+                    .map(|m| (m.message, m.range.start)),
+                search.current,
+                count,
+            ))
+        })
+        .flatten();
+
+    let count = match current {
+        Some((_, _, 0)) => "No matches".to_string(),
+        Some((_, current, count)) => format!("{} of {count}", current + 1),
+        None => String::new(),
+    };
+
+    s.call_on_name("search_count", |view: &mut TextView| {
+        view.set_content(count);
+    });
+
+    let Some((Some((message, position)), _, _)) = current else {
+        return;
+    };
+
+    let (width, height) = s
+        .call_on_name("messages_panel", |panel: &mut MessagesPanel| {
+            let scroll = panel.get_inner_mut().get_inner_mut();
+            (scroll.inner_size().x, scroll.content_viewport().height())
+        })
+        .unwrap();
+
+    // The row of the match is found by wrapping the messages the same way their views do
+    let row = s
+        .call_on_name("messages_container", |view: &mut LinearLayout| {
+            (0..=message.min(view.len().saturating_sub(1)))
+                .filter_map(|i| Some((i, view.get_child(i)?.downcast_ref::<TextView>()?)))
+                .map(|(i, text)| {
+                    let content = text.get_content();
+                    match i == message {
+                        true => rows(&content, width, position).1,
+                        false => rows(&content, width, 0).0,
+                    }
+                })
+                .sum::<usize>()
+        })
+        .unwrap();
+
+    s.call_on_name("messages_panel", |panel: &mut MessagesPanel| {
+        let scroll = panel.get_inner_mut().get_inner_mut();
+
+        // Stop following the end of the conversation, like scrolling up does
+        scroll.set_scroll_strategy(ScrollStrategy::KeepRow);
+        scroll.set_offset((0, row.saturating_sub(height / 2)));
+    });
+}
+
+/// Opens the dialog that searches every saved conversation
+pub fn open_session_search(s: &mut Cursive, query: Option<String>) {
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(
+                    EditView::new()
+                        .content(query.to_owned().unwrap_or_default())
+                        .on_submit(update_session_search)
+                        .with_name("session_query"),
+                )
+                .child(
+                    SelectView::<SessionMatch>::new()
+                        .on_submit(|s, result: &SessionMatch| {
+                            s.pop_layer();
+
+                            match open_session(s, &result.id) {
+                                // Jump to the match in the conversation
+                                Ok(()) => open_search(s, Some(result.term.to_owned())),
+                                Err(error) => show_error(s, error),
+                            }
+                        })
+                        .with_name("session_results")
+                        .scrollable()
+                        .min_height(10),
+                ),
+        )
+        .title("Search sessions")
+        .dismiss_button("Close")
+        .min_width(80),
+    );
+
+    if let Some(query) = query {
+        update_session_search(s, &query);
+    }
+}
+
+/// Lists the saved conversations that match the query, with the text around the first match
+fn update_session_search(s: &mut Cursive, query: &str) {
+    let results = match search_sessions(query) {
+        Ok(results) => results,
+        Err(error) => {
+            show_error(s, error);
+            return;
+        }
+    };
+
+    s.call_on_name("session_results", |view: &mut SelectView<SessionMatch>| {
+        view.clear();
+
+        for result in results {
+            let mut label = StyledString::styled(
+                result.label.to_owned(),
+                Style {
+                    effects: enum_set!(Effect::Bold),
+                    color: ColorStyle::inherit_parent(),
+                },
+            );
+            label.append_plain("  ");
+            label.append_styled(
+                result.snippet.to_owned(),
+                Style {
+                    effects: enum_set!(Effect::Dim),
+                    color: ColorStyle::inherit_parent(),
+                },
+            );
+
+            view.add_item(label, result);
+        }
+    });
+
+    s.focus_name("session_results").ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rendered message with a styled word in the middle
+    fn formatted() -> StyledString {
+        let mut formatted = StyledString::plain("The capital is ");
+        formatted.append_styled("Paris", Effect::Bold);
+        formatted.append_plain(", not Rome.");
+        formatted
+    }
+
+    /// The text of each span of a rendered message, and whether it is highlighted
+    fn spans(formatted: &StyledString) -> Vec<(&str, bool)> {
+        formatted
+            .spans()
+            .map(|span| {
+                let highlighted = span.attr.effects.contains(Effect::Reverse)
+                    || span.attr.color.back == Color::Dark(BaseColor::Yellow).into();
+                (span.content, highlighted)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_queries_literally() {
+        let pattern = pattern("a.b (c)", false, false).unwrap();
+
+        assert!(pattern.is_match("see a.b (c)"));
+        assert!(!pattern.is_match("axb (c)"));
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        assert!(pattern("paris", false, false).unwrap().is_match("Paris"));
+        assert!(!pattern("paris", true, false).unwrap().is_match("Paris"));
+        assert!(pattern("Paris", true, false).unwrap().is_match("Paris"));
+    }
+
+    #[test]
+    fn matches_regexes() {
+        let pattern = pattern(r"r[io]me\b", false, true).unwrap();
+
+        assert!(pattern.is_match("Rome."));
+        assert!(!pattern.is_match("Romeo"));
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        let error = pattern("(unclosed", false, true).err().unwrap();
+        assert!(error.starts_with("Invalid regex"));
+
+        // The same query is fine when it is matched literally
+        assert!(pattern("(unclosed", false, false).is_ok());
+    }
+
+    #[test]
+    fn finds_matches_across_spans() {
+        let pattern = pattern("is paris", false, false).unwrap();
+        assert_eq!(find(&pattern, &formatted()), vec![12..20]);
+    }
+
+    #[test]
+    fn highlights_matches_across_spans() {
+        let highlighted = highlight(&formatted(), &[12..20, 26..30], Some(0));
+
+        assert_eq!(text(&highlighted), text(&formatted()));
+        assert_eq!(
+            spans(&highlighted),
+            vec![
+                ("The capital ", false),
+                ("is ", true),
+                ("Paris", true),
+                (", not ", false),
+                ("Rome", true),
+                (".", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn highlights_the_current_match_differently() {
+        let highlighted = highlight(&formatted(), &[12..14, 26..30], Some(1));
+        let styles: Vec<_> = highlighted.spans().map(|span| *span.attr).collect();
+
+        assert_eq!(spans(&highlighted)[1], ("is", true));
+        assert!(styles[1].effects.contains(Effect::Reverse));
+
+        assert_eq!(spans(&highlighted)[5], ("Rome", true));
+        assert!(!styles[5].effects.contains(Effect::Reverse));
+    }
+
+    #[test]
+    fn finds_the_row_of_a_match() {
+        let formatted = formatted();
+
+        // The capital / is Paris, / not Rome.
+        assert_eq!(rows(&formatted, 12, 0), (3, 0));
+        assert_eq!(rows(&formatted, 12, 15), (3, 1));
+        assert_eq!(rows(&formatted, 12, 26), (3, 2));
+        assert_eq!(rows(&formatted, 100, 26), (1, 0));
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::context::Summary;
+use crate::index::update_index;
+use crate::message::MessageMetadata;
+use crate::title::fallback_title;
+use crate::tree::MessageTree;
+
+/// A conversation as it is saved in the session store
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conversation {
+    pub id: String,
+    pub created_at: DateTime<Local>,
+    /// The profile that was used for the last message
+    pub profile: String,
+    /// The model chosen for this conversation, if it overrides the profile's model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The title written by the model or the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub messages: MessageTree,
+    /// A summary of the messages that no longer fit in the context window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    /// The responses to the summaries and titles requested in the background, which are only
+    /// kept for their usage
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub background_usage: Vec<MessageMetadata>,
+    #[serde(flatten)]
+    pub metadata: SessionMetadata,
+}
+
+/// How a conversation is organized in the sessions sidebar
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SessionMetadata {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Pinned conversations are listed first
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// The folder or project the conversation belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+}
+
+/// Which conversations are listed in the sessions sidebar
+#[derive(Clone, PartialEq)]
+pub enum SessionFilter {
+    Tag(String),
+    Folder(String),
+}
+
+impl SessionFilter {
+    pub fn matches(&self, metadata: &SessionMetadata) -> bool {
+        match self {
+            SessionFilter::Tag(tag) => metadata.tags.contains(tag),
+            SessionFilter::Folder(folder) => metadata.folder.as_ref() == Some(folder),
+        }
+    }
+}
+
+/// A saved conversation as it is listed in the sessions sidebar
+pub struct SessionEntry {
+    pub id: String,
+    pub title: String,
+    pub created_at: DateTime<Local>,
+    pub metadata: SessionMetadata,
+}
+
+impl From<&Conversation> for SessionEntry {
+    fn from(conversation: &Conversation) -> Self {
+        SessionEntry {
+            id: conversation.id.to_owned(),
+            title: conversation
+                .title
+                .to_owned()
+                .or_else(|| fallback_title(&conversation.messages))
+                .unwrap_or_else(|| "Empty chat".to_string()),
+            created_at: conversation.created_at,
+            metadata: conversation.metadata.to_owned(),
+        }
+    }
+}
+
+impl Conversation {
+    pub fn new(profile: &str) -> Conversation {
+        let created_at = Local::now();
+
+        Conversation {
+            // Include milliseconds, since several conversations can be opened at once in tabs
+            id: created_at.format("%Y%m%d-%H%M%S-%3f").to_string(),
+            created_at,
+            profile: profile.to_owned(),
+            model: None,
+            title: None,
+            messages: MessageTree::default(),
+            summary: None,
+            background_usage: vec![],
+            metadata: SessionMetadata::default(),
+        }
+    }
+
+    /// Shortens the active branch to its first `len` messages, so the next message starts a
+    /// new branch
+    pub fn rewind(&mut self, len: usize) {
+        let previous = self.messages.path().to_vec();
+        self.messages.rewind(len);
+        self.drop_stale_summary(&previous);
+    }
+
+    /// Makes the branch containing `node` active
+    pub fn switch_branch(&mut self, node: usize) {
+        let previous = self.messages.path().to_vec();
+        self.messages.switch(node);
+        self.drop_stale_summary(&previous);
+    }
+
+    /// Forgets the summary if it covers messages that are no longer in the active branch
+    fn drop_stale_summary(&mut self, previous: &[usize]) {
+        let unchanged = previous
+            .iter()
+            .zip(self.messages.path())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if self.summary.as_ref().is_some_and(|s| s.until > unchanged) {
+            self.summary = None;
+        }
+    }
+
+    /// Writes the conversation to `<data dir>/chatgpt-tui/sessions/<id>.json`
+    pub fn save(&self) -> Result<(), String> {
+        let dir = sessions_dir().ok_or("Could not find a directory to save sessions in")?;
+
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+
+        let path = dir.join(format!("{}.json", self.id));
+        let contents = serde_json::to_string_pretty(self).unwrap();
+
+        fs::write(&path, contents)
+            .map_err(|e| format!("Could not save {}: {e}", path.display()))?;
+
+        update_index(self)
+    }
+
+    /// Reads a saved conversation by its ID
+    pub fn load(id: &str) -> Result<Conversation, String> {
+        let dir = sessions_dir().ok_or("Could not find the saved sessions")?;
+        let path = dir.join(format!("{id}.json"));
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Could not load {}: {e}", path.display()))
+    }
+}
+
+/// Loads every conversation in the session store, skipping files that can't be read
+pub fn load_conversations() -> Result<Vec<Conversation>, String> {
+    let dir = match sessions_dir() {
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok(vec![]),
+    };
+
+    let entries =
+        fs::read_dir(&dir).map_err(|e| format!("Could not read {}: {e}", dir.display()))?;
+
+    let mut conversations: Vec<Conversation> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter_map(|contents| serde_json::from_str(&contents).ok())
+        .collect();
+
+    conversations.sort_by_key(|c: &Conversation| c.created_at);
+
+    Ok(conversations)
+}
+
+/// Lists the saved conversations, pinned conversations first and then newest first
+pub fn list_sessions() -> Result<Vec<SessionEntry>, String> {
+    let mut sessions: Vec<SessionEntry> = load_conversations()?
+        .iter()
+        .map(SessionEntry::from)
+        .collect();
+
+    sort_sessions(&mut sessions);
+    Ok(sessions)
+}
+
+pub fn sort_sessions(sessions: &mut [SessionEntry]) {
+    sessions.sort_by_key(|s| (!s.metadata.pinned, std::cmp::Reverse(s.created_at)));
+}
+
+/// Reads a comma-separated list of tags, as they are typed in the sidebar
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = vec![];
+
+    for tag in tags.split(',') {
+        let tag = tag.trim().trim_start_matches('#').trim();
+
+        if !tag.is_empty() && !parsed.iter().any(|t| t == tag) {
+            parsed.push(tag.to_owned());
+        }
+    }
+
+    parsed
+}
+
+pub fn sessions_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("chatgpt-tui").join("sessions"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(parse_tags(" #rust, work,,rust, # "), ["rust", "work"]);
+        assert_eq!(parse_tags("## notes"), ["notes"]);
+        assert!(parse_tags("").is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeSet;
+
+use cursive::reexports::enumset::enum_set;
+use cursive::theme::{ColorStyle, Effect, Style};
+use cursive::utils::markup::StyledString;
+use cursive::view::{Nameable, Resizable};
+use cursive::views::{
+    Checkbox, Dialog, EditView, HideableView, LinearLayout, Panel, ResizedView, SelectView,
+    TextView,
+};
+use cursive::Cursive;
+
+use crate::session::{
+    list_sessions, parse_tags, sort_sessions, Conversation, SessionEntry, SessionFilter,
+    SessionMetadata,
+};
+use crate::{show_error, AppState, Request};
+
+type Sidebar = HideableView<ResizedView<Panel<LinearLayout>>>;
+
+/// The width of the sessions sidebar, in columns
+pub const SIDEBAR_WIDTH: usize = 30;
+
+/// Lists the saved conversations in the sidebar
+pub fn load_sessions(s: &mut Cursive) {
+    match list_sessions() {
+        Ok(sessions) => {
+            s.with_user_data(|state: &mut AppState| state.sessions = sessions);
+            update_sidebar(s);
+        }
+        Err(error) => show_error(s, error),
+    }
+}
+
+/// Updates the title and metadata of a tab's conversation in the sidebar, adding it once it
+/// has been saved
+pub fn sync_session(s: &mut Cursive, tab: usize) {
+    s.with_user_data(|state: &mut AppState| {
+        let Some((tab, _)) = state.find_tab(tab) else {
+            return;
+        };
+
+        let (id, title, metadata) = (tab.session.to_owned(), tab.title(), tab.metadata.to_owned());
+
+        if !state.sessions.iter().any(|entry| entry.id == id) {
+            match Conversation::load(&id) {
+                Ok(conversation) => state.sessions.push(SessionEntry::from(&conversation)),
+                Err(_) => return,
+            }
+        }
+
+        if let Some(entry) = state.sessions.iter_mut().find(|entry| entry.id == id) {
+            if let Some(title) = title {
+                entry.title = title;
+            }
+
+            entry.metadata = metadata;
+        }
+
+        sort_sessions(&mut state.sessions);
+    });
+
+    update_sidebar(s);
+}
+
+/// Renders the filters and the conversations that match the selected filter in the sidebar
+pub fn update_sidebar(s: &mut Cursive) {
+    let (filters, selected_filter, sessions) = s
+        .with_user_data(|state: &mut AppState| {
+            let folders: BTreeSet<&String> = state
+                .sessions
+                .iter()
+                .filter_map(|entry| entry.metadata.folder.as_ref())
+                .collect();
+            let tags: BTreeSet<&String> = state
+                .sessions
+                .iter()
+                .flat_map(|entry| &entry.metadata.tags)
+                .collect();
+
+            let mut filters: Vec<(String, Option<SessionFilter>)> =
+                vec![("All conversations".to_string(), None)];
+            filters.extend(folders.into_iter().map(|folder| {
+                (
+                    format!("Folder: {folder}"),
+                    Some(SessionFilter::Folder(folder.to_owned())),
+                )
+            }));
+            filters.extend(
+                tags.into_iter()
+                    .map(|tag| (format!("#{tag}"), Some(SessionFilter::Tag(tag.to_owned())))),
+            );
+
+            // Nothing matches the filter anymore, so show everything again
+            if !filters.iter().any(|(_, f)| *f == state.session_filter) {
+                state.session_filter = None;
+            }
+
+            let selected_filter = filters
+                .iter()
+                .position(|(_, f)| *f == state.session_filter)
+                .unwrap_or_default();
+
+            let sessions: Vec<(StyledString, String)> = state
+                .sessions
+                .iter()
+                .filter(|entry| {
+                    state
+                        .session_filter
+                        .as_ref()
+                        .is_none_or(|filter| filter.matches(&entry.metadata))
+                })
+                .map(|entry| {
+                    let mut label = StyledString::plain(match entry.metadata.pinned {
+                        true => "* ",
+                        false => "",
+                    });
+                    label.append_plain(&entry.title);
+
+                    for tag in &entry.metadata.tags {
+                        label.append_styled(
+                            format!(" #{tag}"),
+                            Style {
+                                effects: enum_set!(Effect::Dim),
+                                color: ColorStyle::inherit_parent(),
+                            },
+                        );
+                    }
+
+                    (label, entry.id.to_owned())
+                })
+                .collect();
+
+            (filters, selected_filter, sessions)
+        })
+        .unwrap();
+
+    s.call_on_name(
+        "session_filter",
+        |view: &mut SelectView<Option<SessionFilter>>| {
+            view.clear();
+            view.add_all(filters);
+            view.set_selection(selected_filter);
+        },
+    );
+
+    s.call_on_name("sessions", |view: &mut SelectView<String>| {
+        // Keep the same conversation selected as the list changes
+        let selected = view.selection();
+
+        view.clear();
+        view.add_all(sessions);
+
+        if let Some(index) = selected.and_then(|id| view.iter().position(|(_, i)| *i == *id)) {
+            view.set_selection(index);
+        }
+    });
+}
+
+/// Shows or hides the sidebar
+pub fn toggle_sidebar(s: &mut Cursive) {
+    let visible = s
+        .call_on_name("sidebar", |view: &mut Sidebar| {
+            view.set_visible(!view.is_visible());
+            view.is_visible()
+        })
+        .unwrap();
+
+    if !visible {
+        s.focus_name("input_box").ok();
+    }
+}
+
+/// The conversation that is selected in the sidebar, along with its metadata
+fn selected_session(s: &mut Cursive) -> Option<(String, SessionMetadata)> {
+    let id = s
+        .call_on_name("sessions", |view: &mut SelectView<String>| view.selection())
+        .flatten()?;
+
+    s.with_user_data(|state: &mut AppState| {
+        let entry = state.sessions.iter().find(|entry| entry.id == *id)?;
+        Some((entry.id.to_owned(), entry.metadata.to_owned()))
+    })
+    .flatten()
+}
+
+/// Pins the conversation selected in the sidebar, or unpins it
+pub fn toggle_pinned(s: &mut Cursive) {
+    if let Some((id, mut metadata)) = selected_session(s) {
+        metadata.pinned = !metadata.pinned;
+        set_metadata(s, &id, metadata);
+    }
+}
+
+/// Opens a dialog to edit the folder and tags of the conversation selected in the sidebar
+pub fn edit_session(s: &mut Cursive) {
+    let Some((id, metadata)) = selected_session(s) else {
+        return;
+    };
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Folder"))
+                .child(
+                    EditView::new()
+                        .content(metadata.folder.to_owned().unwrap_or_default())
+                        .with_name("session_folder"),
+                )
+                .child(TextView::new("Tags, separated by commas"))
+                .child(
+                    EditView::new()
+                        .content(metadata.tags.join(", "))
+                        .with_name("session_tags"),
+                )
+                .child(
+                    LinearLayout::horizontal()
+                        .child(
+                            Checkbox::new()
+                                .with_checked(metadata.pinned)
+                                .with_name("session_pinned"),
+                        )
+                        .child(TextView::new(" Pinned")),
+                ),
+        )
+        .title("Edit session")
+        .button("Save", move |s| {
+            let folder = s
+                .call_on_name("session_folder", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let tags = s
+                .call_on_name("session_tags", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let pinned = s
+                .call_on_name("session_pinned", |view: &mut Checkbox| view.is_checked())
+                .unwrap();
+
+            s.pop_layer();
+
+            let folder = folder.trim();
+            let metadata = SessionMetadata {
+                tags: parse_tags(&tags),
+                pinned,
+                folder: (!folder.is_empty()).then(|| folder.to_owned()),
+            };
+
+            set_metadata(s, &id, metadata);
+        })
+        .dismiss_button("Cancel")
+        .min_width(40),
+    );
+}
+
+/// Saves the tags, folder and pin of a conversation. Open conversations are saved by their
+/// tab, so the change isn't lost when the tab saves the conversation again.
+fn set_metadata(s: &mut Cursive, id: &str, metadata: SessionMetadata) {
+    let open = s
+        .with_user_data(|state: &mut AppState| {
+            let tab = state.tabs.iter_mut().find(|tab| tab.session == id)?;
+            tab.metadata = metadata.to_owned();
+            tab.request_send
+                .send(Request::SetMetadata(metadata.to_owned()))
+                .unwrap();
+            Some(())
+        })
+        .flatten()
+        .is_some();
+
+    if !open {
+        let saved = Conversation::load(id).and_then(|mut conversation| {
+            conversation.metadata = metadata.to_owned();
+            conversation.save()
+        });
+
+        if let Err(error) = saved {
+            show_error(s, error);
+            return;
+        }
+    }
+
+    s.with_user_data(|state: &mut AppState| {
+        if let Some(entry) = state.sessions.iter_mut().find(|entry| entry.id == id) {
+            entry.metadata = metadata;
+        }
+
+        sort_sessions(&mut state.sessions);
+    });
+
+    update_sidebar(s);
+}
@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use cursive::reexports::enumset::enum_set;
+use cursive::theme::{ColorStyle, Effect, Style};
+use cursive::utils::markup::StyledString;
+
+/// How long notifications are shown for
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(3);
+
+/// A request that is waiting for (or streaming) its response
+pub struct PendingResponse {
+    started: Instant,
+    first_token: Option<Instant>,
+    /// Tokens streamed so far
+    tokens: usize,
+}
+
+impl PendingResponse {
+    fn stats(&self) -> Option<ResponseStats> {
+        let first_token = self.first_token?;
+        let streaming = first_token.elapsed().as_secs_f64();
+
+        Some(ResponseStats {
+            time_to_first_token: first_token - self.started,
+            tokens_per_second: (streaming > 0.0).then(|| self.tokens as f64 / streaming),
+        })
+    }
+}
+
+pub struct ResponseStats {
+    time_to_first_token: Duration,
+    tokens_per_second: Option<f64>,
+}
+
+impl ResponseStats {
+    fn describe(&self) -> String {
+        let ttft = format!("TTFT {:.1}s", self.time_to_first_token.as_secs_f64());
+
+        match self.tokens_per_second {
+            Some(speed) => format!("{ttft}, {speed:.1} tokens/s"),
+            None => ttft,
+        }
+    }
+}
+
+/// The state shown in the status bar, apart from what is derived from the conversation
+#[derive(Default)]
+pub struct Status {
+    pub pending: Option<PendingResponse>,
+    last_response: Option<ResponseStats>,
+    notification: Option<(String, Instant)>,
+}
+
+impl Status {
+    pub fn start_response(&mut self) {
+        self.pending = Some(PendingResponse {
+            started: Instant::now(),
+            first_token: None,
+            tokens: 0,
+        });
+    }
+
+    /// Records `tokens` more tokens streamed for the response
+    pub fn add_response_tokens(&mut self, tokens: usize) {
+        if let Some(pending) = &mut self.pending {
+            pending.first_token.get_or_insert_with(Instant::now);
+            pending.tokens += tokens;
+        }
+    }
+
+    /// Whether the response has started streaming
+    pub fn is_streaming(&self) -> bool {
+        matches!(&self.pending, Some(pending) if pending.first_token.is_some())
+    }
+
+    pub fn finish_response(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.last_response = pending.stats();
+        }
+    }
+
+    pub fn notify(&mut self, notification: String) {
+        self.notification = Some((notification, Instant::now()));
+    }
+
+    /// Whether the status changes over time, and needs to be refreshed
+    pub fn is_animated(&self) -> bool {
+        self.pending.is_some() || self.notification.is_some()
+    }
+
+    /// Renders the status bar. `details` are shown after the connection state.
+    pub fn render(&mut self, profile: &str, model: &str, details: &[String]) -> StyledString {
+        if matches!(&self.notification, Some((_, shown)) if shown.elapsed() > NOTIFICATION_DURATION)
+        {
+            self.notification = None;
+        }
+
+        let connection = match &self.pending {
+            Some(pending) => match pending.stats() {
+                Some(stats) => format!(
+                    "Streaming ({:.1}s) | {}",
+                    pending.started.elapsed().as_secs_f64(),
+                    stats.describe()
+                ),
+                None => format!(
+                    "Waiting for response ({:.1}s)",
+                    pending.started.elapsed().as_secs_f64()
+                ),
+            },
+            None => match &self.last_response {
+                Some(stats) => format!("Ready | {}", stats.describe()),
+                None => "Ready".to_string(),
+            },
+        };
+
+        let mut status = StyledString::styled(
+            format!("{profile} ({model})"),
+            Style {
+                effects: enum_set!(Effect::Bold),
+                color: ColorStyle::inherit_parent(),
+            },
+        );
+
+        for part in std::iter::once(&connection).chain(details) {
+            status.append_plain(" | ");
+            status.append_plain(part);
+        }
+
+        if let Some((notification, _)) = &self.notification {
+            status.append_plain("  ");
+            status.append_styled(
+                notification,
+                Style {
+                    effects: enum_set!(Effect::Reverse),
+                    color: ColorStyle::inherit_parent(),
+                },
+            );
+        }
+
+        status
+    }
+}
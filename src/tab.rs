@@ -0,0 +1,146 @@
+use std::sync::mpsc::Sender;
+
+use chrono::Local;
+
+use crate::attach::PendingAttachment;
+use crate::compare::Comparison;
+use crate::config::Profile;
+use crate::format::StreamingMessage;
+use crate::message::{ContentPart, Message, MessageMetadata, Role};
+use crate::session::SessionMetadata;
+use crate::status::Status;
+use crate::title::fallback_title;
+use crate::tree::MessageTree;
+use crate::Request;
+
+/// The longest label shown in the tab bar, in characters
+const LABEL_WIDTH: usize = 24;
+
+/// A message that waits to be sent until the current response has finished
+pub struct QueuedMessage {
+    /// Identifies the message while it is edited, since its position changes as the queue is sent
+    pub id: usize,
+    pub message: Message,
+}
+
+/// A conversation that is open in a tab. Only the current tab is shown, the others keep
+/// receiving their responses in the background.
+pub struct Tab {
+    /// Identifies the tab in the updates from its request thread, since tabs can be closed
+    pub id: usize,
+    /// The ID the conversation is saved under
+    pub session: String,
+    /// The title written by the model or the user
+    pub title: Option<String>,
+    /// The tags, folder and pin of the conversation, as they are shown in the sidebar
+    pub metadata: SessionMetadata,
+    pub active_profile: String,
+    /// The model chosen for this conversation, overriding the profile's model
+    pub model: Option<String>,
+    /// The number of tokens used by the conversation, as of the last request
+    pub context_tokens: usize,
+    /// The index of the first message that is still in the context window
+    pub context_start: usize,
+    /// The messages of the conversation, whose active branch is shown
+    pub messages: MessageTree,
+    pub request_send: Sender<Request>,
+    /// Whether a message has been sent, and its response hasn't finished yet
+    pub busy: bool,
+    /// Messages that are sent in order once the current response has finished
+    pub queue: Vec<QueuedMessage>,
+    /// The response that is being streamed, which is the last message
+    pub streaming: Option<StreamingMessage>,
+    pub status: Status,
+    /// The contents of the input box while another tab is shown
+    pub draft: String,
+    /// The files attached with `/attach`, which are sent with the next message
+    pub attachments: Vec<PendingAttachment>,
+    /// The images added with `/image`, which are sent with the next message
+    pub images: Vec<ContentPart>,
+    /// Whether a response arrived while another tab was shown
+    pub unseen: bool,
+    /// The profiles that messages are sent to instead of the active profile, to compare
+    /// their responses
+    pub compare: Vec<Profile>,
+    /// The comparison that is waiting for a response to be chosen
+    pub comparison: Option<Comparison>,
+}
+
+impl Tab {
+    pub fn new(
+        id: usize,
+        session: String,
+        active_profile: String,
+        model: Option<String>,
+        request_send: Sender<Request>,
+    ) -> Tab {
+        Tab {
+            id,
+            session,
+            title: None,
+            metadata: SessionMetadata::default(),
+            active_profile,
+            model,
+            context_tokens: 0,
+            context_start: 0,
+            messages: MessageTree::default(),
+            request_send,
+            busy: false,
+            queue: vec![],
+            streaming: None,
+            status: Status::default(),
+            draft: String::new(),
+            attachments: vec![],
+            images: vec![],
+            unseen: false,
+            compare: vec![],
+            comparison: None,
+        }
+    }
+
+    /// Creates a user message that is sent with the active profile and model of the tab
+    pub fn user_message(&self, content: String) -> Message {
+        Message {
+            role: Role::User,
+            content,
+            metadata: MessageMetadata {
+                profile: Some(self.active_profile.to_owned()),
+                model: self.model.to_owned(),
+                created_at: Some(Local::now()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A copy of a message that was sent before, to send it again with the active profile and
+    /// model of the tab
+    pub fn resent_message(&self, message: &Message) -> Message {
+        Message {
+            metadata: MessageMetadata {
+                attachments: message.metadata.attachments.to_owned(),
+                ..self.user_message(String::new()).metadata
+            },
+            ..message.to_owned()
+        }
+    }
+
+    /// The title of the conversation, or the start of the first message until it has one
+    pub fn title(&self) -> Option<String> {
+        self.title
+            .to_owned()
+            .or_else(|| fallback_title(&self.messages))
+    }
+
+    /// The label shown in the tab bar, which is the title shortened to fit
+    pub fn label(&self) -> String {
+        match self.title() {
+            Some(title) if title.chars().count() > LABEL_WIDTH => {
+                let start: String = title.chars().take(LABEL_WIDTH - 3).collect();
+                format!("{}...", start.trim_end())
+            }
+            Some(title) => title,
+            None => "New chat".to_string(),
+        }
+    }
+}
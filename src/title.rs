@@ -0,0 +1,124 @@
+use serde::Deserialize;
+use surf::Client;
+
+use crate::api::complete_response;
+use crate::config::{Config, Profile};
+use crate::message::{Message, MessageMetadata, Role};
+use crate::tree::MessageTree;
+
+const TITLE_PROMPT: &str = "Write a title of at most six words for the following conversation between a user and an AI assistant. Reply with the title only, without quotes.";
+
+/// The most characters of each message that are sent to write the title
+const TITLE_EXCERPT: usize = 2000;
+
+/// The longest title that is kept, in characters
+const TITLE_LENGTH: usize = 80;
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TitleConfig {
+    /// Whether the model titles conversations after the first response
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The profile that writes the titles, instead of the conversation's profile
+    pub profile: Option<String>,
+    /// The model that writes the titles, e.g. one that is cheaper than the conversation's
+    pub model: Option<String>,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        TitleConfig {
+            enabled: default_enabled(),
+            profile: None,
+            model: None,
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// The profile that writes the title of a conversation held with `profile`, if titles are
+/// enabled
+pub fn title_profile(config: &Config, profile: &Profile) -> Option<Profile> {
+    if !config.titles.enabled {
+        return None;
+    }
+
+    let mut title_profile = match &config.titles.profile {
+        Some(name) => config.profile(name)?.to_owned(),
+        None => profile.to_owned(),
+    };
+
+    if let Some(model) = &config.titles.model {
+        title_profile.set_model(model.to_owned());
+    }
+
+    Some(title_profile)
+}
+
+/// The title shown until the conversation has one, which is the first line the user wrote
+pub fn fallback_title(messages: &MessageTree) -> Option<String> {
+    messages
+        .iter()
+        .find(|m| matches!(m.role, Role::User))
+        .and_then(|m| m.content.trim().lines().next())
+        .map(str::to_owned)
+}
+
+/// Asks the model for a short title for the conversation. Returns the title along with the
+/// metadata of the response, which has its usage.
+pub async fn generate_title(
+    client: &Client,
+    profile: &Profile,
+    messages: &[Message],
+) -> Result<(String, MessageMetadata), String> {
+    let mut transcript = String::new();
+
+    for m in messages {
+        let speaker = match m.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+            Role::System | Role::Tool => continue,
+        };
+
+        let excerpt: String = m.content.chars().take(TITLE_EXCERPT).collect();
+        transcript.push_str(&format!("{speaker}: {excerpt}\n\n"));
+    }
+
+    let request = [
+        Message {
+            role: Role::System,
+            content: TITLE_PROMPT.to_string(),
+            ..Default::default()
+        },
+        Message {
+            role: Role::User,
+            content: transcript,
+            ..Default::default()
+        },
+    ];
+
+    let response = complete_response(client, profile, &request)
+        .await
+        .map_err(|e| format!("Could not title the conversation: {e}"))?;
+
+    // Models like to quote titles or end them with a period, despite the prompt
+    let title: String = response
+        .content
+        .trim()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim_matches(|c: char| c == '"' || c == '\'' || c == '*' || c == '.' || c.is_whitespace())
+        .chars()
+        .take(TITLE_LENGTH)
+        .collect();
+
+    match title.is_empty() {
+        true => Err("Could not title the conversation: the response was empty".to_string()),
+        false => Ok((title, response.metadata)),
+    }
+}
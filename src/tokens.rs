@@ -0,0 +1,71 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+
+use crate::message::Message;
+
+/// Every message is wrapped in `<|start|>{role}\n{content}<|end|>\n`
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Every reply is primed with `<|start|>assistant<|message|>`
+pub const TOKENS_PER_REPLY: usize = 3;
+
+/// Returns the BPE encoding used by the model. Models that aren't from OpenAI (or are unknown)
+/// use cl100k, which gives a close enough estimate for other tokenizers.
+fn encoding(model: &str) -> &'static CoreBPE {
+    match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+        _ => cl100k_base_singleton(),
+    }
+}
+
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    encoding(model).encode_with_special_tokens(text).len()
+}
+
+/// Counts the tokens that a single message adds to a request. Messages that are only shown
+/// aren't sent, so they add none.
+pub fn message_tokens(model: &str, message: &Message) -> usize {
+    if message.metadata.local {
+        return 0;
+    }
+
+    TOKENS_PER_MESSAGE + count_tokens(model, &message.sent_content())
+}
+
+/// Counts the tokens that `messages` will use when they are sent as a request
+pub fn count_message_tokens(model: &str, messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| message_tokens(model, m))
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+/// Returns the context window of well-known models, in tokens
+pub fn context_limit(model: &str) -> Option<usize> {
+    let limits: &[(&str, usize)] = &[
+        ("gpt-4.1", 1_047_576),
+        ("gpt-4o", 128_000),
+        ("chatgpt-4o", 128_000),
+        ("gpt-4-turbo", 128_000),
+        ("gpt-4-1106", 128_000),
+        ("gpt-4-0125", 128_000),
+        ("gpt-4-32k", 32_768),
+        ("gpt-4", 8_192),
+        ("gpt-3.5-turbo", 16_385),
+        ("o1", 200_000),
+        ("o3", 200_000),
+        ("o4", 200_000),
+        ("claude", 200_000),
+        ("llama3.1", 131_072),
+        ("llama3.2", 131_072),
+        ("llama3", 8_192),
+        ("llama2", 4_096),
+        ("mistral", 32_768),
+    ];
+
+    limits
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, limit)| *limit)
+}
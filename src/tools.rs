@@ -0,0 +1,504 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use chrono::Local;
+use cursive::view::{Nameable, Resizable, Scrollable};
+use cursive::views::{Checkbox, Dialog, LinearLayout, TextView};
+use cursive::Cursive;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::message::{Message, MessageMetadata, Role, ToolCall};
+use crate::patch::resolve;
+use crate::run::{run_process, RUN_TIMEOUT};
+use crate::search::pattern;
+use crate::{update_status, AppState, Request};
+
+/// The largest file that is read or searched, in bytes
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+/// The most matching lines that a search returns
+const MAX_MATCHES: usize = 200;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ToolConfig {
+    /// Whether the model can ask to call local tools (OpenAI and Azure profiles only)
+    #[serde(default)]
+    pub enabled: bool,
+    /// The commands the model can run, e.g. `git status`. Arguments may follow them.
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+}
+
+/// The local tools that the model can call
+#[derive(Clone, Copy)]
+enum Tool {
+    ReadFile,
+    ListDirectory,
+    Grep,
+    RunCommand,
+}
+
+const TOOLS: [Tool; 4] = [
+    Tool::ReadFile,
+    Tool::ListDirectory,
+    Tool::Grep,
+    Tool::RunCommand,
+];
+
+#[derive(Deserialize)]
+struct PathArguments {
+    #[serde(default = "current_dir")]
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct GrepArguments {
+    pattern: String,
+    #[serde(default = "current_dir")]
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct CommandArguments {
+    command: String,
+}
+
+fn current_dir() -> String {
+    ".".to_string()
+}
+
+impl Tool {
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::ReadFile => "read_file",
+            Tool::ListDirectory => "list_directory",
+            Tool::Grep => "grep",
+            Tool::RunCommand => "run_command",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Tool> {
+        TOOLS.into_iter().find(|tool| tool.name() == name)
+    }
+
+    /// The function that is sent to the model, with the JSON schema of its arguments
+    fn definition(&self, config: &ToolConfig) -> Value {
+        let path = json!({
+            "type": "string",
+            "description": "A path relative to the current directory",
+        });
+
+        let (description, parameters) = match self {
+            Tool::ReadFile => (
+                "Read a text file in the current directory".to_string(),
+                json!({
+                    "type": "object",
+                    "properties": { "path": path },
+                    "required": ["path"],
+                }),
+            ),
+            Tool::ListDirectory => (
+                "List the files in a directory. Directories end with a slash.".to_string(),
+                json!({
+                    "type": "object",
+                    "properties": { "path": path },
+                }),
+            ),
+            Tool::Grep => (
+                "Find the lines that match a regular expression in a file, or in the files of \
+                 a directory and its subdirectories"
+                    .to_string(),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "pattern": { "type": "string", "description": "A regular expression" },
+                        "path": path,
+                    },
+                    "required": ["pattern"],
+                }),
+            ),
+            Tool::RunCommand => (
+                format!(
+                    "Run a command in the current directory, without a shell, and get its output. \
+                     The command must start with one of: {}",
+                    config.allowed_commands.join(", ")
+                ),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": { "type": "string", "description": "The command and its arguments" },
+                    },
+                    "required": ["command"],
+                }),
+            ),
+        };
+
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name(),
+                "description": description,
+                "parameters": parameters,
+            },
+        })
+    }
+}
+
+/// The tools that are sent with requests. `run_command` is left out unless some commands are
+/// allowed.
+pub fn tool_definitions(config: &ToolConfig) -> Vec<Value> {
+    if !config.enabled {
+        return vec![];
+    }
+
+    TOOLS
+        .iter()
+        .filter(|tool| !matches!(tool, Tool::RunCommand) || !config.allowed_commands.is_empty())
+        .map(|tool| tool.definition(config))
+        .collect()
+}
+
+/// Parses the arguments of a call. Tools without required arguments may be called with none.
+fn arguments<T: DeserializeOwned>(call: &ToolCall) -> Result<T, String> {
+    let arguments = match call.function.arguments.trim() {
+        "" => "{}",
+        arguments => arguments,
+    };
+
+    serde_json::from_str(arguments)
+        .map_err(|e| format!("Invalid arguments for {}: {e}", call.function.name))
+}
+
+/// Describes what a call would do, to ask the user before it is run
+fn describe_call(call: &ToolCall) -> String {
+    let description = match Tool::from_name(&call.function.name) {
+        Some(Tool::ReadFile) => {
+            arguments(call).map(|args: PathArguments| format!("Read the file `{}`", args.path))
+        }
+        Some(Tool::ListDirectory) => {
+            arguments(call).map(|args: PathArguments| format!("List the files in `{}`", args.path))
+        }
+        Some(Tool::Grep) => arguments(call)
+            .map(|args: GrepArguments| format!("Search for `{}` in `{}`", args.pattern, args.path)),
+        Some(Tool::RunCommand) => {
+            arguments(call).map(|args: CommandArguments| format!("Run `{}`", args.command))
+        }
+        None => Err(String::new()),
+    };
+
+    description.unwrap_or_else(|_| {
+        format!(
+            "Call {} with {}",
+            call.function.name, call.function.arguments
+        )
+    })
+}
+
+/// Runs a call that the user approved, and returns the result that is sent to the model.
+/// Errors are sent to the model as well, so it can try something else.
+fn run_tool(config: &ToolConfig, call: &ToolCall) -> String {
+    let result = match Tool::from_name(&call.function.name) {
+        Some(Tool::ReadFile) => {
+            arguments(call).and_then(|args: PathArguments| read_file(&args.path))
+        }
+        Some(Tool::ListDirectory) => {
+            arguments(call).and_then(|args: PathArguments| list_directory(&args.path))
+        }
+        Some(Tool::Grep) => {
+            arguments(call).and_then(|args: GrepArguments| grep(&args.pattern, &args.path))
+        }
+        Some(Tool::RunCommand) => {
+            arguments(call).and_then(|args: CommandArguments| run_command(config, &args.command))
+        }
+        None => Err(format!("There is no tool named {}", call.function.name)),
+    };
+
+    result.unwrap_or_else(|error| format!("Error: {error}"))
+}
+
+/// Reads a file, if it is small enough and is text
+fn read_text(file: &Path) -> Result<String, String> {
+    let size = fs::metadata(file).map_err(|e| e.to_string())?.len();
+
+    if size > MAX_FILE_SIZE {
+        return Err(format!(
+            "it is {} KB, and at most {} KB can be read",
+            size.div_ceil(1024),
+            MAX_FILE_SIZE / 1024
+        ));
+    }
+
+    fs::read_to_string(file).map_err(|e| e.to_string())
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    read_text(&resolve(path)?).map_err(|e| format!("Could not read {path}: {e}"))
+}
+
+fn list_directory(path: &str) -> Result<String, String> {
+    let entries =
+        fs::read_dir(resolve(path)?).map_err(|e| format!("Could not list {path}: {e}"))?;
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            match entry.path().is_dir() {
+                true => format!("{name}/"),
+                false => name,
+            }
+        })
+        .collect();
+
+    names.sort();
+    Ok(names.join("\n"))
+}
+
+fn grep(query: &str, path: &str) -> Result<String, String> {
+    let regex = pattern(query, true, true)?;
+    let mut matches = vec![];
+
+    search_path(&regex, &resolve(path)?, PathBuf::from(path), &mut matches);
+
+    let mut result = matches
+        .iter()
+        .take(MAX_MATCHES)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // The search stops soon after it has enough matches, so the rest aren't counted
+    match matches.len() {
+        0 => result = "No matches".to_string(),
+        count if count > MAX_MATCHES => result.push_str("\n[more matches left out]"),
+        _ => {}
+    }
+
+    Ok(result)
+}
+
+/// Adds the matching lines of a file, or of the files in a directory, as `path:line:text`.
+/// Hidden files, symbolic links, and files that aren't text are skipped.
+fn search_path(regex: &Regex, file: &Path, shown: PathBuf, matches: &mut Vec<String>) {
+    if matches.len() > MAX_MATCHES {
+        return;
+    }
+
+    if file.is_dir() {
+        let Ok(entries) = fs::read_dir(file) else {
+            return;
+        };
+
+        let mut entries: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter(|entry| entry.file_type().is_ok_and(|kind| !kind.is_symlink()))
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            search_path(regex, &entry.path(), shown.join(entry.file_name()), matches);
+        }
+
+        return;
+    }
+
+    let Ok(content) = read_text(file) else {
+        return;
+    };
+
+    let shown = shown
+        .strip_prefix(".")
+        .unwrap_or(&shown)
+        .display()
+        .to_string();
+
+    for (i, line) in content.lines().enumerate() {
+        if regex.is_match(line) {
+            matches.push(format!("{shown}:{}:{line}", i + 1));
+        }
+    }
+}
+
+/// Runs a command that starts with one of the allowed commands
+fn run_command(config: &ToolConfig, command: &str) -> Result<String, String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+
+    let allowed = config.allowed_commands.iter().any(|allowed| {
+        let allowed: Vec<&str> = allowed.split_whitespace().collect();
+        !allowed.is_empty() && words.starts_with(&allowed)
+    });
+
+    if !allowed {
+        return Err(format!("`{command}` is not an allowed command"));
+    }
+
+    let mut process = Command::new(words[0]);
+    process.args(&words[1..]);
+
+    run_process(process, RUN_TIMEOUT)
+}
+
+/// Asks which of the tools that a response called may be run. Declined calls are answered
+/// as such, so the model can go on without them.
+pub fn confirm_tool_calls(s: &mut Cursive, tab: usize, calls: Vec<ToolCall>) {
+    let label = s
+        .with_user_data(|state: &mut AppState| {
+            let (tab, current) = state.find_tab(tab)?;
+            Some((!current).then(|| tab.label()))
+        })
+        .flatten();
+
+    // Calls for a tab that was closed in the meantime are dropped
+    let Some(label) = label else {
+        return;
+    };
+
+    let mut list = LinearLayout::vertical();
+    for (i, call) in calls.iter().enumerate() {
+        list.add_child(
+            LinearLayout::horizontal()
+                .child(
+                    Checkbox::new()
+                        .checked()
+                        .with_name(format!("tool_call_{tab}_{i}")),
+                )
+                .child(TextView::new(format!(" {}", describe_call(call)))),
+        );
+    }
+
+    let title = match label {
+        Some(label) => format!("Run these tools for \"{label}\"?"),
+        None => "Run these tools?".to_string(),
+    };
+
+    let declined = calls.to_owned();
+
+    s.add_layer(
+        Dialog::around(list.scrollable().max_height(15))
+            .title(title)
+            .button("Run", move |s| {
+                let approved = (0..calls.len())
+                    .map(|i| {
+                        s.call_on_name(&format!("tool_call_{tab}_{i}"), |view: &mut Checkbox| {
+                            view.is_checked()
+                        })
+                        .unwrap_or_default()
+                    })
+                    .collect();
+
+                s.pop_layer();
+                run_tool_calls(s, tab, calls.to_owned(), approved);
+            })
+            .button("Decline", move |s| {
+                s.pop_layer();
+                run_tool_calls(s, tab, declined.to_owned(), vec![]);
+            })
+            .max_width(100),
+    );
+}
+
+/// Runs the approved tool calls in the background, and sends all of their results to the
+/// conversation of a tab
+fn run_tool_calls(s: &mut Cursive, tab: usize, calls: Vec<ToolCall>, approved: Vec<bool>) {
+    let context = s
+        .with_user_data(|state: &mut AppState| {
+            let config = state.config.tools.to_owned();
+            let (tab, _) = state.find_tab(tab)?;
+
+            // The results are sent with the profile and model of the response that called
+            // the tools
+            let response = tab.messages.iter().last()?.metadata.to_owned();
+
+            if approved.contains(&true) {
+                tab.status.notify("Running the tools".to_string());
+            }
+
+            Some((config, response, tab.request_send.to_owned()))
+        })
+        .flatten();
+
+    let Some((config, response, request_send)) = context else {
+        return;
+    };
+
+    update_status(s);
+
+    thread::spawn(move || {
+        let results = calls
+            .iter()
+            .enumerate()
+            .map(|(i, call)| Message {
+                role: Role::Tool,
+                content: match approved.get(i) {
+                    Some(true) => run_tool(&config, call),
+                    _ => "The user declined to run this tool".to_string(),
+                },
+                tool_call_id: Some(call.id.to_owned()),
+                metadata: MessageMetadata {
+                    profile: response.profile.to_owned(),
+                    model: response.model.to_owned(),
+                    created_at: Some(Local::now()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect();
+
+        request_send.send(Request::ToolResults(results)).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ToolConfig {
+        ToolConfig {
+            enabled: true,
+            allowed_commands: vec!["git status".to_string(), "echo".to_string()],
+        }
+    }
+
+    #[test]
+    fn runs_allowed_commands() {
+        let output = run_command(&config(), "echo hello there").unwrap();
+        assert!(output.contains("hello there"));
+    }
+
+    #[test]
+    fn rejects_other_commands() {
+        let config = config();
+
+        for command in [
+            "rm -rf target",
+            "git",
+            "git statusx",
+            "gitstatus",
+            "git push",
+            "",
+        ] {
+            assert_eq!(
+                run_command(&config, command).unwrap_err(),
+                format!("`{command}` is not an allowed command")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_everything_without_allowed_commands() {
+        let config = ToolConfig {
+            enabled: true,
+            allowed_commands: vec![" ".to_string()],
+        };
+
+        assert!(run_command(&config, "echo hi").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::ops::Index;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::Message;
+
+/// A message in the tree, along with the message it replies to
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Node {
+    pub message: Message,
+    pub parent: Option<usize>,
+}
+
+/// The messages of a conversation. Editing or regenerating a message adds a sibling branch
+/// instead of replacing it, so no message is lost. The active branch, from the first message to
+/// `head`, is what is shown and sent to the model.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(from = "SavedTree")]
+pub struct MessageTree {
+    /// Every message, after the message it replies to
+    nodes: Vec<Node>,
+    /// The last message of the active branch
+    head: Option<usize>,
+    /// The nodes of the active branch, in order
+    #[serde(skip)]
+    path: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedTree {
+    Tree {
+        nodes: Vec<Node>,
+        head: Option<usize>,
+    },
+    /// Sessions saved before conversations could branch
+    Flat(Vec<Message>),
+}
+
+impl From<SavedTree> for MessageTree {
+    fn from(saved: SavedTree) -> MessageTree {
+        match saved {
+            SavedTree::Tree { nodes, head } => {
+                let mut tree = MessageTree {
+                    nodes,
+                    head,
+                    path: vec![],
+                };
+                tree.update_path();
+                tree
+            }
+            SavedTree::Flat(messages) => {
+                let mut tree = MessageTree::default();
+                messages.into_iter().for_each(|m| tree.push(m));
+                tree
+            }
+        }
+    }
+}
+
+impl Index<usize> for MessageTree {
+    type Output = Message;
+
+    /// The message at `index` in the active branch
+    fn index(&self, index: usize) -> &Message {
+        &self.nodes[self.path[index]].message
+    }
+}
+
+impl MessageTree {
+    /// The number of messages in the active branch
+    pub fn len(&self) -> usize {
+        self.path.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    /// The nodes of the active branch, in order
+    pub fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    /// The messages of the active branch
+    pub fn iter(&self) -> impl Iterator<Item = &Message> {
+        self.path.iter().map(|&node| &self.nodes[node].message)
+    }
+
+    pub fn to_vec(&self) -> Vec<Message> {
+        self.iter().cloned().collect()
+    }
+
+    /// Every message in the tree, including the ones on other branches
+    pub fn all(&self) -> impl Iterator<Item = &Message> {
+        self.nodes.iter().map(|node| &node.message)
+    }
+
+    pub fn last_mut(&mut self) -> Option<&mut Message> {
+        let head = self.head?;
+        Some(&mut self.nodes[head].message)
+    }
+
+    /// Adds a message to the end of the active branch
+    pub fn push(&mut self, message: Message) {
+        self.nodes.push(Node {
+            message,
+            parent: self.head,
+        });
+
+        self.head = Some(self.nodes.len() - 1);
+        self.path.push(self.nodes.len() - 1);
+    }
+
+    /// Removes the last message of the active branch. The message is only deleted if nothing was
+    /// added after it (e.g. a message whose request failed), otherwise its branch is kept.
+    pub fn pop(&mut self) -> Option<Message> {
+        let node = self.path.pop()?;
+        self.head = self.path.last().copied();
+
+        if node == self.nodes.len() - 1 {
+            self.nodes.pop().map(|node| node.message)
+        } else {
+            Some(self.nodes[node].message.to_owned())
+        }
+    }
+
+    /// Shortens the active branch to its first `len` messages, so the next message starts a new
+    /// branch
+    pub fn rewind(&mut self, len: usize) {
+        self.path.truncate(len);
+        self.head = self.path.last().copied();
+    }
+
+    /// Makes the branch containing `node` active, following its most recent replies
+    pub fn switch(&mut self, node: usize) {
+        let mut head = node;
+
+        while let Some(&child) = self.children(Some(head)).last() {
+            head = child;
+        }
+
+        self.head = Some(head);
+        self.update_path();
+    }
+
+    /// The replies to `parent`, or the first messages of each branch if it is `None`
+    pub fn children(&self, parent: Option<usize>) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&node| self.nodes[node].parent == parent)
+            .collect()
+    }
+
+    /// The alternatives to the message at `index` in the active branch, including itself
+    pub fn siblings(&self, index: usize) -> Vec<usize> {
+        self.children(self.nodes[self.path[index]].parent)
+    }
+
+    fn update_path(&mut self) {
+        self.path.clear();
+
+        let mut node = self.head;
+        while let Some(n) = node {
+            self.path.push(n);
+            node = self.nodes[n].parent;
+        }
+
+        self.path.reverse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message {
+            content: content.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn contents(tree: &MessageTree) -> Vec<&str> {
+        tree.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn pushes_and_pops() {
+        let mut tree = MessageTree::default();
+        tree.push(message("a"));
+        tree.push(message("b"));
+
+        assert_eq!(contents(&tree), ["a", "b"]);
+        assert_eq!(tree.pop().unwrap().content, "b");
+        assert_eq!(contents(&tree), ["a"]);
+        assert_eq!(tree.all().count(), 1);
+    }
+
+    #[test]
+    fn keeps_branches() {
+        let mut tree = MessageTree::default();
+        tree.push(message("a"));
+        tree.push(message("b"));
+        tree.rewind(1);
+        tree.push(message("c"));
+
+        assert_eq!(contents(&tree), ["a", "c"]);
+        assert_eq!(tree.siblings(1), [1, 2]);
+        assert_eq!(tree.children(Some(0)), [1, 2]);
+
+        // Popping a message that isn't the last node only leaves its branch
+        tree.rewind(1);
+        tree.push(message("d"));
+        tree.switch(2);
+        assert_eq!(tree.pop().unwrap().content, "c");
+        assert_eq!(tree.all().count(), 4);
+
+        tree.switch(1);
+        assert_eq!(contents(&tree), ["a", "b"]);
+        assert_eq!(tree.path(), [0, 1]);
+    }
+
+    #[test]
+    fn switches_to_the_latest_reply() {
+        let mut tree = MessageTree::default();
+        tree.push(message("a"));
+        tree.push(message("b"));
+        tree.rewind(0);
+        tree.push(message("c"));
+        tree.push(message("d"));
+        tree.push(message("e"));
+        tree.rewind(2);
+        tree.push(message("f"));
+
+        tree.switch(2);
+        assert_eq!(contents(&tree), ["c", "d", "f"]);
+
+        tree.switch(0);
+        assert_eq!(contents(&tree), ["a", "b"]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut tree = MessageTree::default();
+        tree.push(message("a"));
+        tree.push(message("b"));
+        tree.rewind(1);
+        tree.push(message("c"));
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: MessageTree = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(contents(&loaded), ["a", "c"]);
+        assert_eq!(loaded.all().count(), 3);
+    }
+
+    #[test]
+    fn loads_flat_conversations() {
+        let tree: MessageTree = serde_json::from_str(
+            r#"[{"role": "user", "content": "a"}, {"role": "assistant", "content": "b"}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(contents(&tree), ["a", "b"]);
+        assert_eq!(tree.path(), [0, 1]);
+        assert_eq!(tree.siblings(0), [0]);
+    }
+}
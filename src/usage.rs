@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+use crate::message::{Message, Role, Usage};
+use crate::session::{load_conversations, Conversation};
+
+/// The price of a model, in dollars per million tokens
+#[derive(Deserialize, Clone)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Looks up the price of a model. Prices are matched by the longest model name prefix, so
+/// `gpt-4o` also covers `gpt-4o-2024-08-06`.
+fn price<'a>(prices: &'a BTreeMap<String, Price>, model: &str) -> Option<&'a Price> {
+    prices
+        .iter()
+        .filter(|(name, _)| model.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price)
+}
+
+fn cost(price: &Price, usage: &Usage) -> f64 {
+    (usage.prompt_tokens as f64 * price.prompt + usage.completion_tokens as f64 * price.completion)
+        / 1_000_000.0
+}
+
+/// The total cost of the responses in `messages`. Returns `None` if none of them have a price.
+pub fn conversation_cost<'a>(
+    prices: &BTreeMap<String, Price>,
+    messages: impl IntoIterator<Item = &'a Message>,
+) -> Option<f64> {
+    messages
+        .into_iter()
+        .filter_map(|m| {
+            let usage = m.metadata.usage.as_ref()?;
+            let price = price(prices, m.metadata.model.as_deref()?)?;
+            Some(cost(price, usage))
+        })
+        .reduce(|a, b| a + b)
+}
+
+#[derive(Default)]
+struct UsageTotal {
+    responses: usize,
+    /// Responses whose provider didn't report their usage, which Azure doesn't when streaming
+    unreported: usize,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: Option<f64>,
+}
+
+/// Aggregates the usage of every saved conversation by day and model
+pub fn usage_report(prices: &BTreeMap<String, Price>) -> Result<String, String> {
+    Ok(report(prices, &load_conversations()?))
+}
+
+fn report(prices: &BTreeMap<String, Price>, conversations: &[Conversation]) -> String {
+    let mut totals: BTreeMap<(NaiveDate, String), UsageTotal> = BTreeMap::new();
+
+    for conversation in conversations {
+        // Responses on every branch were paid for, as were summaries and titles
+        let responses = conversation
+            .messages
+            .all()
+            .filter(|m| matches!(m.role, Role::Assistant))
+            .map(|m| &m.metadata)
+            .chain(&conversation.background_usage);
+
+        for metadata in responses {
+            let day = metadata
+                .created_at
+                .unwrap_or(conversation.created_at)
+                .date_naive();
+            let model = metadata.model.to_owned().unwrap_or_default();
+
+            let total = totals.entry((day, model.to_owned())).or_default();
+            total.responses += 1;
+
+            let Some(usage) = &metadata.usage else {
+                total.unreported += 1;
+                continue;
+            };
+
+            total.prompt_tokens += usage.prompt_tokens;
+            total.completion_tokens += usage.completion_tokens;
+
+            if let Some(price) = price(prices, &model) {
+                *total.cost.get_or_insert(0.0) += cost(price, usage);
+            }
+        }
+    }
+
+    if totals.is_empty() {
+        return "No usage has been recorded yet.".to_string();
+    }
+
+    let mut report = format!(
+        "{:<10}  {:<28}  {:>9}  {:>10}  {:>10}  {:>9}\n",
+        "Day", "Model", "Responses", "Prompt", "Completion", "Cost"
+    );
+    let mut day_cost: Option<f64> = None;
+    let mut total_cost: Option<f64> = None;
+    let mut unreported = 0;
+
+    // Newest days first
+    let rows: Vec<_> = totals.iter().rev().collect();
+
+    for (i, ((day, model), total)) in rows.iter().enumerate() {
+        report.push_str(&format!(
+            "{:<10}  {:<28}  {:>9}  {:>10}  {:>10}  {:>9}\n",
+            day.format("%Y-%m-%d"),
+            model,
+            match total.unreported {
+                0 => total.responses.to_string(),
+                _ => format!("{}*", total.responses),
+            },
+            total.prompt_tokens,
+            total.completion_tokens,
+            format_cost(total.cost),
+        ));
+
+        unreported += total.unreported;
+
+        if let Some(cost) = total.cost {
+            *day_cost.get_or_insert(0.0) += cost;
+            *total_cost.get_or_insert(0.0) += cost;
+        }
+
+        // Add a subtotal after the last model of each day
+        if rows.get(i + 1).map(|(key, _)| key.0) != Some(*day) {
+            if let Some(cost) = day_cost.take() {
+                report.push_str(&format!(
+                    "{:>86}\n",
+                    format!("Day total: {}", format_cost(Some(cost)))
+                ));
+            }
+        }
+    }
+
+    report.push_str(&format!("\nTotal: {}", format_cost(total_cost)));
+
+    if unreported > 0 {
+        report.push_str(&format!(
+            "\n\n* Usage unavailable for {unreported} of the responses, as the provider didn't \
+             report it (Azure doesn't for streamed responses)"
+        ));
+    }
+
+    report
+}
+
+pub fn format_cost(cost: Option<f64>) -> String {
+    match cost {
+        Some(cost) if cost < 0.01 => format!("${cost:.4}"),
+        Some(cost) => format!("${cost:.2}"),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::*;
+    use crate::message::MessageMetadata;
+
+    fn prices() -> BTreeMap<String, Price> {
+        [("gpt-4o", 2.5), ("gpt-4o-mini", 0.15), ("o1", 15.0)]
+            .into_iter()
+            .map(|(name, prompt)| {
+                let price = Price {
+                    prompt,
+                    completion: prompt * 4.0,
+                };
+                (name.to_owned(), price)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_the_longest_prefix() {
+        let prices = prices();
+
+        assert_eq!(price(&prices, "gpt-4o").unwrap().prompt, 2.5);
+        assert_eq!(price(&prices, "gpt-4o-2024-08-06").unwrap().prompt, 2.5);
+        assert_eq!(
+            price(&prices, "gpt-4o-mini-2024-07-18").unwrap().prompt,
+            0.15
+        );
+        assert!(price(&prices, "gpt-4").is_none());
+        assert!(price(&prices, "claude-3-5-sonnet").is_none());
+    }
+
+    #[test]
+    fn costs_per_million_tokens() {
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+        };
+
+        assert_eq!(cost(&prices()["gpt-4o"], &usage), 7.5);
+    }
+
+    fn response(model: &str, usage: Option<Usage>) -> Message {
+        Message {
+            role: Role::Assistant,
+            content: "Paris.".to_string(),
+            metadata: MessageMetadata {
+                model: Some(model.to_owned()),
+                created_at: Some(Local.with_ymd_and_hms(2024, 11, 5, 12, 0, 0).unwrap()),
+                usage,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn marks_responses_without_usage() {
+        let mut conversation = Conversation::new("azure");
+        conversation.messages.push(response(
+            "gpt-4o",
+            Some(Usage {
+                prompt_tokens: 1000,
+                completion_tokens: 100,
+            }),
+        ));
+        conversation.messages.push(response("gpt-4o", None));
+
+        let report = report(&prices(), &[conversation]);
+        let row = report.lines().nth(1).unwrap();
+
+        assert!(row.starts_with("2024-11-05  gpt-4o"));
+        assert_eq!(
+            row.split_whitespace().collect::<Vec<_>>()[2..],
+            ["2*", "1000", "100", "$0.0035"]
+        );
+        assert!(report.ends_with(
+            "* Usage unavailable for 1 of the responses, as the provider didn't report it \
+             (Azure doesn't for streamed responses)"
+        ));
+    }
+
+    #[test]
+    fn reports_when_nothing_is_missing() {
+        let mut conversation = Conversation::new("openai");
+        conversation.messages.push(response(
+            "gpt-4o-mini",
+            Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 10,
+            }),
+        ));
+
+        let report = report(&prices(), &[conversation]);
+        assert!(!report.contains('*'));
+        assert!(report.contains("gpt-4o-mini"));
+    }
+}